@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::{DateTime, Utc};
+use password_hash::{rand_core::OsRng, SaltString};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::permissions::Role;
+use crate::types::UserId;
+
+
+/// Minimum amount of characters a password needs to have
+pub const MIN_PASSWORD_LEN : usize = 8;
+
+
+/// A registered user. Only the salted argon2 hash of the password is kept
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Account {
+    pub id: UserId,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+    /// What the user is on the whole server, as given with /role
+    #[serde(default)]
+    pub role: Role,
+}
+
+
+/// Everything that can go wrong when registering or logging in
+#[derive(Debug)]
+pub enum AccountError {
+    AlreadyRegistered(String),
+    UnknownUser(String),
+    WrongPassword,
+    /// What the client is told about either of the two above, so nobody can find out which names are registered
+    LoginFailed,
+    PasswordTooShort,
+    Hashing(String),
+    Io(io::Error),
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::AlreadyRegistered(name) => write!(f, "{} is already registered", name),
+            AccountError::UnknownUser(name) => write!(f, "There is no account called {}", name),
+            AccountError::WrongPassword => write!(f, "Wrong password"),
+            AccountError::LoginFailed => write!(f, "Wrong username or password"),
+            AccountError::PasswordTooShort => write!(f, "The password needs at least {} characters", MIN_PASSWORD_LEN),
+            AccountError::Hashing(err) => write!(f, "Could not hash the password: {}", err),
+            AccountError::Io(err) => write!(f, "Could not save the account: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<io::Error> for AccountError {
+    fn from(err: io::Error) -> Self {
+        AccountError::Io(err)
+    }
+}
+
+
+/// Hashes the password with a freshly generated salt.
+/// This is slow on purpose, so don't call it while holding the lock on `Shared`
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(AccountError::PasswordTooShort);
+    };
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| AccountError::Hashing(err.to_string()))
+}
+
+
+/// Checks the password against the hash of an account.
+/// This is slow on purpose, so don't call it while holding the lock on `Shared`
+pub fn verify_password(account: &Account, password: &str) -> Result<(), AccountError> {
+    let hash = PasswordHash::new(&account.password_hash)
+        .map_err(|err| AccountError::Hashing(err.to_string()))?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .map_err(|_| AccountError::WrongPassword)
+}
+
+
+/// All registered accounts, keyed by their lowercase username.
+/// If the store has a path, every change gets written to it as JSON
+#[derive(Debug, Default)]
+pub struct AccountStore {
+    path: Option<PathBuf>,
+    accounts: HashMap<String, Account>,
+}
+
+impl AccountStore {
+    /// A store that forgets everything once the server stops
+    pub fn in_memory() -> AccountStore {
+        AccountStore::default()
+    }
+
+
+    /// Loads the accounts from the file, which gets created on the first registration
+    pub fn open(path: &Path) -> io::Result<AccountStore> {
+        let accounts = match fs::read_to_string(path) {
+            Ok(content) => {
+                let accounts : Vec<Account> = serde_json::from_str(&content)?;
+                accounts.into_iter().map(|account| (account.username.to_lowercase(), account)).collect()
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(AccountStore {
+            path: Some(path.to_path_buf()),
+            accounts,
+        })
+    }
+
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(&username.to_lowercase())
+    }
+
+
+    pub fn get_by_id(&self, id: UserId) -> Option<&Account> {
+        self.accounts.values().find(|account| account.id == id)
+    }
+
+
+    /// The highest id any account has, so that new users don't get the same one
+    pub fn max_id(&self) -> Option<UserId> {
+        self.accounts.values().map(|account| account.id).max()
+    }
+
+
+    /// Adds a new account with an already hashed password (see `hash_password`)
+    pub fn register(&mut self, id: UserId, username: &str, password_hash: String) -> Result<&Account, AccountError> {
+        let key = username.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            return Err(AccountError::AlreadyRegistered(username.to_string()));
+        };
+        self.accounts.insert(key.clone(), Account {
+            id,
+            username: username.to_string(),
+            password_hash,
+            created_at: Utc::now(),
+            role: Role::default(),
+        });
+        if let Err(err) = self.save() {
+            self.accounts.remove(&key);
+            return Err(err.into());
+        };
+        Ok(&self.accounts[&key])
+    }
+
+
+    /// Changes the username of an account, e.g. after a /nick
+    pub fn rename(&mut self, id: UserId, new_name: &str) -> Result<(), AccountError> {
+        let old_key = match self.get_by_id(id) {
+            Some(account) => account.username.to_lowercase(),
+            None => return Ok(()),
+        };
+        let new_key = new_name.to_lowercase();
+        if new_key != old_key && self.accounts.contains_key(&new_key) {
+            return Err(AccountError::AlreadyRegistered(new_name.to_string()));
+        };
+        let mut account = self.accounts.remove(&old_key).unwrap();
+        let old_name = std::mem::replace(&mut account.username, new_name.to_string());
+        self.accounts.insert(new_key.clone(), account);
+        if let Err(err) = self.save() {
+            let mut account = self.accounts.remove(&new_key).unwrap();
+            account.username = old_name;
+            self.accounts.insert(old_key, account);
+            return Err(err.into());
+        };
+        Ok(())
+    }
+
+
+    /// Changes the role of an account, e.g. after a /role
+    pub fn set_role(&mut self, id: UserId, role: Role) -> Result<(), AccountError> {
+        let account = match self.accounts.values_mut().find(|account| account.id == id) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let previous = std::mem::replace(&mut account.role, role);
+        if let Err(err) = self.save() {
+            if let Some(account) = self.accounts.values_mut().find(|account| account.id == id) {
+                account.role = previous;
+            };
+            return Err(err.into());
+        };
+        Ok(())
+    }
+
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut accounts : Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by_key(|account| account.id);
+        fs::write(path, serde_json::to_string_pretty(&accounts)?)
+    }
+}
@@ -1,11 +1,13 @@
-use std::{error::Error, collections::VecDeque, time::Duration};
+use std::{error::Error, collections::{BTreeMap, BTreeSet, HashSet, VecDeque}, time::{Duration, Instant}};
 use futures::pin_mut;
 use tokio::{
     sync::mpsc::{self, Sender, Receiver},
     net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}},
     io::{AsyncWriteExt, BufReader, BufWriter, AsyncBufReadExt},
 };
-use std::io;
+use std::io::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
@@ -14,44 +16,800 @@ use crossterm::{
 };
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
     Frame, Terminal,
 };
 
+use chrono::{Local, Utc};
+
 use rusty_chat::{
     DEFAULT_ADDR,
     message::Message,
-    types::{MessageType, UserSetupType},
+    types::{MessageType, UserSetupType, MessageId, RequestId, Receipt, Presence, Status, TransferId},
+    transfer::{self, Assembly, FileInfo, CHUNK_SIZE},
+    protocol::{self, Capability, PROTOCOL_VERSION},
+    command::{Command, CommandOutcome, HistoryPosition},
 };
 
 
+/// Everything this client can do, if the server supports it as well
+const CLIENT_CAPABILITIES : [Capability; 6] = [
+    Capability::History,
+    Capability::Search,
+    Capability::Typing,
+    Capability::Receipts,
+    Capability::Presence,
+    Capability::FileTransfer,
+];
+/// Amount of older messages requested at once when scrolling past the top
+const HISTORY_BATCH : usize = 50;
+/// Amount of messages shown around a search result when jumping to it
+const CONTEXT_BATCH : usize = 21;
+/// Characters of the replied to message that are quoted above a reply
+const SNIPPET_LENGTH : usize = 40;
+/// How often the room gets told that we are still typing
+const TYPING_INTERVAL : Duration = Duration::from_secs(3);
+/// How long someone is shown as typing after their last notice
+const TYPING_TIMEOUT : Duration = Duration::from_secs(5);
+/// Rooms with at most this many members show who has read up to which message
+const SMALL_ROOM : usize = 5;
+/// How long without any input until we tell everyone we are away
+const AWAY_AFTER : Duration = Duration::from_secs(5 * 60);
+
+
+/// Results of a /search, shown instead of the messages until closed with Esc
+struct SearchView {
+    query: String,
+    results: Vec<Message>,
+    selected: usize,
+    /// The messages around the selected result, once the user jumped to it
+    context: Option<Vec<Message>>,
+    /// Whether we are waiting for the messages around the selected result
+    context_pending: bool,
+}
+
+impl SearchView {
+    fn new(query: String, results: Vec<Message>) -> SearchView {
+        SearchView {
+            query,
+            results,
+            selected: 0,
+            context: None,
+            context_pending: false,
+        }
+    }
+
+
+    fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.results.len() {
+            self.selected += 1;
+        };
+    }
+
+
+    /// Asks the server for the messages around the selected result
+    fn jump_to_selected(&mut self) -> Option<Message> {
+        let result = self.results.get(self.selected)?;
+        let (id, room) = (result.id?, result.room.clone()?);
+        self.context_pending = true;
+        Some(Message {
+            text: format!("/history {} around {} in #{}", CONTEXT_BATCH, id, room),
+            msg_type: MessageType::Command(Command::History {
+                count: Some(CONTEXT_BATCH),
+                position: Some(HistoryPosition::Around(id)),
+                room: Some(format!("#{}", room)),
+            }),
+            room: Some(room),
+            ..Message::new()
+        })
+    }
+}
+
+
+/// A message with every reply to it, opened with /thread and shown instead of the messages until closed with Esc
+struct ThreadView {
+    root: MessageId,
+    messages: Vec<Message>,
+}
+
+impl ThreadView {
+    /// Adds the message if it is a reply in this thread
+    fn add(&mut self, msg: &Message) {
+        let in_thread = msg.thread == Some(self.root)
+            || msg.reply_to.is_some_and(|parent| self.messages.iter().any(|known| known.id == Some(parent)));
+        if in_thread {
+            self.messages.push(msg.clone());
+        };
+    }
+}
+
+
+/// A file we offered, sent a chunk at a time so chatting goes on meanwhile
+struct Upload {
+    /// Request id of the offer, until the server gave the transfer its id
+    request_id: Option<RequestId>,
+    id: Option<TransferId>,
+    file: FileInfo,
+    data: Vec<u8>,
+    next_chunk: u64,
+}
+
+impl Upload {
+    /// The next chunk to send, once the server is ready for them
+    fn next_chunk(&mut self) -> Option<Message> {
+        let chunk = transfer::chunk(self.id?, &self.data, self.next_chunk)?;
+        self.next_chunk += 1;
+        Some(Message {
+            msg_type: chunk,
+            ..Message::new()
+        })
+    }
+
+
+    fn progress(&self) -> u64 {
+        (self.next_chunk * CHUNK_SIZE as u64).min(self.file.size) * 100 / self.file.size.max(1)
+    }
+}
+
+
+/// A file we accepted, with where to save it
+struct Download {
+    dest: Option<String>,
+    /// The chunks that arrived so far, once the server started sending them
+    assembly: Option<Assembly>,
+}
+
+impl Download {
+    fn progress(&self) -> Option<(&str, u64)> {
+        let assembly = self.assembly.as_ref()?;
+        Some((&assembly.file.name, assembly.received() * 100 / assembly.file.size.max(1)))
+    }
+}
+
+
+#[derive(Default)]
 struct App {
+    /// Our own username, as confirmed by the server
+    username: String,
     input: String,
     messages: VecDeque<Message>,
+    /// Room our messages currently go to, as told by the server
+    current_room: Option<String>,
+    /// Who sent us the last private message, so /r can reply to them
+    last_dm_from: Option<String>,
+    /// How many messages the view is scrolled up from the newest one
+    scroll: usize,
+    /// Whether we are waiting for a /history answer
+    history_pending: bool,
+    /// Rooms the server has no older messages of
+    history_exhausted: HashSet<String>,
+    search: Option<SearchView>,
+    thread: Option<ThreadView>,
+    /// What the server agreed on in the handshake
+    capabilities: BTreeSet<Capability>,
+    /// Request id of the last command we sent
+    last_request_id: RequestId,
+    /// Commands the server hasn't answered yet, as they were typed
+    pending_commands: BTreeMap<RequestId, String>,
+    /// When we last told the room that we are typing
+    last_typing_notice: Option<Instant>,
+    /// Who is typing in which room, keyed by room and username, and when we heard of it last
+    typing: BTreeMap<(String, String), Instant>,
+    /// The receipts we last sent for each room
+    sent_receipts: BTreeMap<String, Receipt>,
+    /// How far the members of each room got, by room and username
+    receipts: BTreeMap<String, BTreeMap<String, Receipt>>,
+    /// The status of everyone sharing a room with us (and our own), by username
+    presence: BTreeMap<String, Status>,
+    /// When the user last pressed a key
+    last_input: Option<Instant>,
+    /// Whether we set the user to away because they didn't type anything for a while
+    auto_away: bool,
+    /// How many messages mentioned us since we last looked at /mentions
+    unread_mentions: usize,
+    /// Files we are sending, oldest first. Only the first one gets chunks sent at a time
+    uploads: Vec<Upload>,
+    /// Files we are receiving, by transfer id
+    downloads: BTreeMap<TransferId, Download>,
 }
 
-impl Default for App {
-    fn default() -> App {
-        App {
-            input: String::new(),
-            messages: VecDeque::new(),
+impl App {
+    /// Id of the oldest message of the current room we know of
+    fn oldest_message_id(&self) -> Option<MessageId> {
+        self.messages.iter()
+            .filter(|msg| msg.room.is_some() && msg.room == self.current_room)
+            .find_map(|msg| msg.id)
+    }
+
+
+    /// Scrolls the message view up. Once the top is reached, older messages are requested
+    /// from the server. Returns the /history command to send, if any
+    fn scroll_up(&mut self, amount: usize, view_height: usize) -> Option<Message> {
+        let max_scroll = self.messages.len().saturating_sub(view_height);
+        if self.scroll < max_scroll {
+            self.scroll = (self.scroll + amount).min(max_scroll);
+            return None;
+        };
+        let room = self.current_room.clone()?;
+        if !self.capabilities.contains(&Capability::History) {
+            return None;
+        };
+        if self.history_pending || self.history_exhausted.contains(&room) {
+            return None;
+        };
+        self.history_pending = true;
+        let position = self.oldest_message_id().map(HistoryPosition::Before);
+        Some(Message {
+            text: format!("/history {}", HISTORY_BATCH),
+            msg_type: MessageType::Command(Command::History {
+                count: Some(HISTORY_BATCH),
+                position,
+                room: Some(format!("#{}", room)),
+            }),
+            room: Some(room),
+            ..Message::new()
+        })
+    }
+
+
+    fn scroll_down(&mut self, amount: usize) {
+        self.scroll = self.scroll.saturating_sub(amount);
+    }
+
+
+    /// Puts the older messages of a /history answer in front of the ones we already have
+    fn prepend_history(&mut self, room: Option<String>, messages: Vec<Message>, has_more: bool) {
+        self.history_pending = false;
+        let known : HashSet<MessageId> = self.messages.iter().filter_map(|msg| msg.id).collect();
+        for msg in messages.into_iter().rev() {
+            if msg.id.is_none_or(|id| !known.contains(&id)) {
+                self.messages.push_front(msg);
+            };
+        };
+        if let (Some(room), false) = (room, has_more) {
+            if self.history_exhausted.insert(room.clone()) {
+                self.messages.push_front(Message {
+                    text: format!("--- Beginning of #{} ---", room),
+                    msg_type: MessageType::SystemInfo,
+                    ..Message::new()
+                });
+            };
+        };
+    }
+
+
+    /// Gives the message a request id, so the copy the server sends back can be matched to our local one
+    fn tag_message(&mut self, msg: &mut Message) {
+        self.last_request_id += 1;
+        msg.request_id = Some(self.last_request_id);
+    }
+
+
+    /// Gives the command a request id, so the answer of the server can be matched to it
+    fn track_command(&mut self, msg: &mut Message) {
+        self.tag_message(msg);
+        // Passwords are never shown
+        let label = match msg.msg_type {
+            MessageType::Command(Command::Register { .. }) => String::from("/register"),
+            _ => msg.text.clone(),
+        };
+        self.pending_commands.insert(self.last_request_id, label);
+    }
+
+
+    /// Marks the command as answered. A failure is shown right below the command that caused it
+    fn finish_command(&mut self, request_id: Option<RequestId>, command: &str, outcome: CommandOutcome) {
+        let Some(request_id) = request_id else {
+            return;
+        };
+        let typed = self.pending_commands.remove(&request_id).unwrap_or_else(|| format!("/{}", command));
+        let CommandOutcome::Failed(reason) = outcome else {
+            return;
+        };
+        // Nothing is coming for the requests that wait for an answer
+        if command == "history" {
+            self.history_pending = false;
+            if let Some(search) = &mut self.search {
+                search.context_pending = false;
+            };
+        };
+        let error = Message {
+            text: format!("{} failed: {}", typed, reason),
+            msg_type: MessageType::Error,
+            request_id: Some(request_id),
+            ..Message::new()
+        };
+        match self.messages.iter().rposition(|msg| msg.request_id == Some(request_id)) {
+            Some(position) => self.messages.insert(position + 1, error),
+            None => self.messages.push_back(error),
+        };
+    }
+
+
+    /// Turns `/edit [message-id] <text>` and `/delete [message-id]` into the message for the server, or None for any other input.
+    /// Without an id they are about our last message in the current room. An edit starting with a number
+    /// followed by more text is about the message with that id
+    fn change_request(&self, input: &str, username: &str) -> Option<Result<Message, String>> {
+        let (name, rest) = input.split_once(' ').unwrap_or((input, ""));
+        let rest = rest.trim();
+        let last_own = self.messages.iter().rev()
+            .filter(|msg| msg.msg_type == MessageType::User && msg.author == username && !msg.deleted)
+            .find(|msg| msg.id.is_some() && msg.room.is_some() && msg.room == self.current_room)
+            .and_then(|msg| msg.id);
+        let with_id = rest.split_once(' ').and_then(|(id, text)| Some((id.parse().ok()?, text.trim())));
+        let (id, text) = match name {
+            "/edit" if rest.is_empty() => return Some(Err(String::from("Usage: /edit [message-id] <new text>"))),
+            "/edit" => match with_id {
+                Some((id, text)) => (Some(id), text.to_string()),
+                None => (last_own, rest.to_string()),
+            },
+            "/delete" if rest.is_empty() => (last_own, String::new()),
+            "/delete" => match rest.parse() {
+                Ok(id) => (Some(id), String::new()),
+                Err(_) => return Some(Err(String::from("Usage: /delete [message-id]"))),
+            },
+            _ => return None,
+        };
+        let Some(id) = id else {
+            return Some(Err(String::from("You haven't sent anything in this room yet")));
+        };
+        let msg_type = if name == "/edit" { MessageType::Edit { id } } else { MessageType::Delete { id } };
+        // The change goes to the room of the message, which isn't necessarily the current one
+        let room = self.find_message(id).and_then(|msg| msg.room.clone()).or_else(|| self.current_room.clone());
+        Some(Ok(Message {
+            text,
+            msg_type,
+            author: username.to_string(),
+            room,
+            ..Message::new()
+        }))
+    }
+
+
+    /// Turns `/reply <message-id> <text>` into a message answering the one with the id, or None for any other input
+    fn reply_request(&self, input: &str, username: &str) -> Option<Result<Message, String>> {
+        let (name, rest) = input.split_once(' ').unwrap_or((input, ""));
+        if name != "/reply" {
+            return None;
+        };
+        let usage = || Err(String::from("Usage: /reply <message-id> <text>"));
+        let Some((id, text)) = rest.trim().split_once(' ') else {
+            return Some(usage());
+        };
+        let Ok(id) = id.parse() else {
+            return Some(usage());
+        };
+        // The reply goes to the room of the message, which isn't necessarily the current one
+        let room = self.find_message(id).and_then(|parent| parent.room.clone()).or_else(|| self.current_room.clone());
+        Some(Ok(Message {
+            text: text.trim().to_string(),
+            msg_type: MessageType::User,
+            author: username.to_string(),
+            room,
+            reply_to: Some(id),
+            ..Message::new()
+        }))
+    }
+
+
+    /// The message with the id, if we have it in the messages or the open thread
+    fn find_message(&self, id: MessageId) -> Option<&Message> {
+        self.messages.iter()
+            .chain(self.thread.iter().flat_map(|thread| thread.messages.iter()))
+            .find(|msg| msg.id == Some(id))
+    }
+
+
+    /// Applies an edit, deletion or change of the reactions or replies the server passed on to every copy of the message we have
+    fn apply_change(&mut self, id: MessageId, change: &Message) {
+        // An edit that mentions us for the first time counts like a new message that does
+        let newly_mentioned = matches!(change.msg_type, MessageType::Edit { .. })
+            && change.author != self.username
+            && self.mentions_us(change)
+            && self.messages.iter().any(|msg| msg.id == Some(id) && !self.mentions_us(msg));
+        if newly_mentioned {
+            self.add_mention();
+        };
+        let search = self.search.iter_mut()
+            .flat_map(|search| search.results.iter_mut().chain(search.context.iter_mut().flatten()));
+        let thread = self.thread.iter_mut().flat_map(|thread| thread.messages.iter_mut());
+        for msg in self.messages.iter_mut().chain(search).chain(thread).filter(|msg| msg.id == Some(id)) {
+            match change.msg_type {
+                MessageType::Edit { .. } => {
+                    msg.text = change.text.clone();
+                    msg.edited = change.timestamp;
+                    msg.mentions = change.mentions.clone();
+                },
+                MessageType::Delete { .. } => {
+                    msg.text.clear();
+                    msg.deleted = true;
+                },
+                MessageType::ReactionUpdate { ref reactions, .. } => msg.reactions = reactions.clone(),
+                MessageType::ThreadUpdate { reply_count, .. } => msg.reply_count = reply_count,
+                _ => (),
+            };
+        };
+    }
+
+
+    /// A notice for the current room that we are typing a message, unless we told it recently enough.
+    /// Commands aren't shown to anyone, so typing them doesn't count
+    fn typing_notice(&mut self) -> Option<Message> {
+        let writing = !self.input.is_empty() && (!self.input.starts_with('/') || self.input.starts_with("/reply "));
+        if !writing || !self.capabilities.contains(&Capability::Typing) {
+            return None;
+        };
+        if self.last_typing_notice.is_some_and(|sent| sent.elapsed() < TYPING_INTERVAL) {
+            return None;
+        };
+        let room = self.current_room.clone()?;
+        self.last_typing_notice = Some(Instant::now());
+        Some(Message {
+            msg_type: MessageType::Typing,
+            room: Some(room),
+            ..Message::new()
+        })
+    }
+
+
+    /// Who is typing in the current room right now
+    fn typing_in_current_room(&self) -> Vec<&str> {
+        self.typing.iter()
+            .filter(|((room, _), since)| Some(room) == self.current_room.as_ref() && since.elapsed() < TYPING_TIMEOUT)
+            .map(|((_, username), _)| username.as_str())
+            .collect()
+    }
+
+
+    /// Receipts for the rooms we got further in since we last told the server.
+    /// Messages only count as read while the current room is shown scrolled to the newest message
+    fn receipt_updates(&mut self) -> Vec<Message> {
+        if !self.capabilities.contains(&Capability::Receipts) {
+            return Vec::new();
+        };
+        let mut newest : BTreeMap<&String, MessageId> = BTreeMap::new();
+        for msg in self.messages.iter() {
+            if let (Some(room), Some(id)) = (&msg.room, msg.id) {
+                let newest_id = newest.entry(room).or_insert(id);
+                *newest_id = id.max(*newest_id);
+            };
+        };
+        let showing_newest = self.scroll == 0 && self.search.is_none() && self.thread.is_none();
+        let mut updates = Vec::new();
+        for (room, id) in newest {
+            let read = showing_newest && Some(room) == self.current_room.as_ref();
+            let receipt = Receipt {
+                delivered: Some(id),
+                read: if read { Some(id) } else { None },
+            };
+            let sent = self.sent_receipts.entry(room.clone()).or_default();
+            if sent.furthest(receipt) != *sent {
+                *sent = sent.furthest(receipt);
+                updates.push(Message {
+                    msg_type: MessageType::Receipt(*sent),
+                    room: Some(room.clone()),
+                    ..Message::new()
+                });
+            };
+        };
+        updates
+    }
+
+
+    /// Who has read up to which message, in rooms small enough to show it.
+    /// Everyone is put at the newest message we have that they have read
+    fn seen_by(&self) -> BTreeMap<MessageId, Vec<&str>> {
+        let mut seen_by : BTreeMap<MessageId, Vec<&str>> = BTreeMap::new();
+        for (room, receipts) in self.receipts.iter().filter(|(_, receipts)| receipts.len() <= SMALL_ROOM) {
+            for (username, receipt) in receipts.iter().filter(|(username, _)| **username != self.username) {
+                let last_read = self.messages.iter().rev()
+                    .filter(|msg| msg.room.as_ref() == Some(room))
+                    .find_map(|msg| msg.id.filter(|id| Some(*id) <= receipt.read));
+                if let Some(id) = last_read {
+                    seen_by.entry(id).or_default().push(username);
+                };
+            };
+        };
+        seen_by
+    }
+
+
+    /// Ticks for our own messages: one once the server has it, two once every other member got it.
+    /// The ticks turn green once everyone has read it
+    fn delivery_ticks(&self, msg: &Message) -> Option<Span<'static>> {
+        let id = msg.id?;
+        if msg.author != self.username || msg.msg_type != MessageType::User {
+            return None;
+        };
+        let others : Vec<&Receipt> = msg.room.as_ref()
+            .and_then(|room| self.receipts.get(room))
+            .into_iter()
+            .flat_map(|receipts| receipts.iter())
+            .filter(|(username, _)| **username != self.username)
+            .map(|(_, receipt)| receipt)
+            .collect();
+        let delivered = !others.is_empty() && others.iter().all(|receipt| receipt.delivered >= Some(id));
+        let read = !others.is_empty() && others.iter().all(|receipt| receipt.read >= Some(id));
+        let (ticks, color) = match (delivered, read) {
+            (_, true) => ("  ✓✓", Color::Green),
+            (true, false) => ("  ✓✓", Color::DarkGray),
+            (false, false) => ("  ✓", Color::DarkGray),
+        };
+        Some(Span::styled(ticks, Style::default().fg(color)))
+    }
+
+
+    /// Sets the user to away after a while without input, and back to online with the next key.
+    /// Only away set this way is taken back, one chosen with /status stays. Returns the /status to send, if any
+    fn auto_away(&mut self, key_pressed: bool) -> Option<Message> {
+        if !self.capabilities.contains(&Capability::Presence) {
+            return None;
+        };
+        if key_pressed {
+            self.last_input = Some(Instant::now());
+        };
+        let idle = self.last_input.get_or_insert_with(Instant::now).elapsed() >= AWAY_AFTER;
+        let online = self.presence.get(&self.username).is_none_or(|status| status.presence == Presence::Online);
+        let presence = match (self.auto_away, idle) {
+            (true, false) => Presence::Online,
+            (false, true) if online => Presence::Away,
+            _ => return None,
+        };
+        self.auto_away = presence == Presence::Away;
+        Some(Message {
+            text: format!("/status {}", presence),
+            msg_type: MessageType::Command(Command::Status { presence: Some(presence), text: None }),
+            ..Message::new()
+        })
+    }
+
+
+    /// Whether the message mentions us
+    fn mentions_us(&self, msg: &Message) -> bool {
+        msg.mentions.iter().any(|name| name.eq_ignore_ascii_case(&self.username))
+    }
+
+
+    /// Counts a new mention of us and rings the bell, unless we asked not to be disturbed
+    fn add_mention(&mut self) {
+        self.unread_mentions += 1;
+        if self.presence.get(&self.username).is_none_or(|status| status.presence != Presence::DoNotDisturb) {
+            ring_bell();
+        };
+    }
+
+
+    /// A dot in the color of the presence of the user, if we know it
+    fn presence_dot(&self, username: &str) -> Span<'static> {
+        let color = match self.presence.get(username).map(|status| status.presence) {
+            Some(Presence::Online) => Color::Green,
+            Some(Presence::Away) => Color::Yellow,
+            Some(Presence::DoNotDisturb) => Color::Red,
+            Some(Presence::Offline) => Color::DarkGray,
+            None => return Span::raw("  "),
+        };
+        Span::styled("● ", Style::default().fg(color))
+    }
+
+
+    /// Turns `/send <path> [to <username> | to #<room>]` into the offer for the server, or None for any other input.
+    /// The file is read right away and kept until it is uploaded
+    fn send_request(&mut self, input: &str, username: &str) -> Option<Result<Message, String>> {
+        if !input.starts_with("/send ") {
+            return None;
+        };
+        let (path, to) = match Command::parse(input) {
+            Ok(Command::Send { path, to }) => (path, to),
+            Ok(_) => return None,
+            Err(err) => return Some(Err(err.to_string())),
+        };
+        if !self.capabilities.contains(&Capability::FileTransfer) {
+            return Some(Err(String::from("The server doesn't support sending files")));
+        };
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) => return Some(Err(format!("Could not read {}: {}", path, err))),
+        };
+        let file = FileInfo::new(Path::new(&path), &data);
+        let (room, recipient) = match to {
+            Some(room) if room.starts_with('#') => (Some(room.trim_start_matches('#').to_string()), None),
+            Some(user) => (None, Some(user)),
+            None => (self.current_room.clone(), None),
+        };
+        let mut msg = Message {
+            text: input.to_string(),
+            msg_type: MessageType::FileOffer { id: None, file: file.clone() },
+            author: username.to_string(),
+            room,
+            recipient,
+            ..Message::new()
+        };
+        self.track_command(&mut msg);
+        self.uploads.push(Upload { request_id: msg.request_id, id: None, file, data, next_chunk: 0 });
+        Some(Ok(msg))
+    }
+
+
+    /// Handles the answers of the server to our uploads and the files we download.
+    /// Returns what should be shown about it
+    fn handle_transfer(&mut self, msg: Message) -> Option<Message> {
+        match msg.msg_type {
+            MessageType::UploadReady { id } => {
+                if let Some(request_id) = msg.request_id {
+                    self.pending_commands.remove(&request_id);
+                };
+                if let Some(upload) = self.uploads.iter_mut().find(|upload| upload.request_id.is_some() && upload.request_id == msg.request_id) {
+                    upload.id = Some(id);
+                };
+                None
+            },
+            MessageType::TransferDone { id } => {
+                self.uploads.retain(|upload| upload.id != Some(id));
+                Some(Message { msg_type: MessageType::SystemInfo, ..msg })
+            },
+            // The offer itself didn't work out
+            MessageType::TransferFailed { id: None } => {
+                self.uploads.retain(|upload| upload.request_id.is_none() || upload.request_id != msg.request_id);
+                self.finish_command(msg.request_id, "send", CommandOutcome::Failed(msg.text));
+                None
+            },
+            MessageType::TransferFailed { id: Some(id) } => {
+                let name = match self.uploads.iter().position(|upload| upload.id == Some(id)) {
+                    Some(position) => Some(self.uploads.remove(position).file.name),
+                    None => self.downloads.remove(&id).and_then(|download| download.assembly).map(|assembly| assembly.file.name),
+                };
+                Some(Message {
+                    text: format!("Transfer of {} failed: {}", name.unwrap_or_else(|| id.to_string()), msg.text),
+                    msg_type: MessageType::Error,
+                    ..msg
+                })
+            },
+            MessageType::DownloadStart { id, file } => {
+                self.downloads.entry(id).or_insert(Download { dest: None, assembly: None }).assembly = Some(Assembly::new(file));
+                Some(Message { msg_type: MessageType::SystemInfo, ..msg })
+            },
+            MessageType::FileChunk { id, seq, data, checksum } => {
+                let assembly = self.downloads.get_mut(&id)?.assembly.as_mut()?;
+                let name = assembly.file.name.clone();
+                let (text, msg_type) = match assembly.add_chunk(seq, &data, &checksum) {
+                    Ok(None) => return None,
+                    Ok(Some(data)) => {
+                        let download = self.downloads.remove(&id)?;
+                        match save_download(download.dest.as_deref(), &name, &data) {
+                            Ok(path) => (format!("Saved {} to {}", name, path.display()), MessageType::SystemInfo),
+                            Err(err) => (format!("Could not save {}: {}", name, err), MessageType::Error),
+                        }
+                    },
+                    Err(err) => {
+                        self.downloads.remove(&id);
+                        (format!("Download of {} failed: {}", name, err), MessageType::Error)
+                    },
+                };
+                Some(Message { text, msg_type, ..Message::new() })
+            },
+            _ => None,
         }
     }
+
+
+    /// How far the running uploads and downloads got, for the status line
+    fn transfer_progress(&self) -> Vec<String> {
+        let uploads = self.uploads.iter()
+            .filter(|upload| upload.id.is_some())
+            .map(|upload| format!("↑ {} {}%", upload.file.name, upload.progress()));
+        let downloads = self.downloads.values()
+            .filter_map(Download::progress)
+            .map(|(name, progress)| format!("↓ {} {}%", name, progress));
+        uploads.chain(downloads).collect()
+    }
+
+
+    /// The server sends our own messages back with their id and time, which our local copy is missing.
+    /// It is found by the request id we gave the message
+    fn confirm_own_message(&mut self, confirmed: Message) {
+        if confirmed.request_id.is_none() {
+            return;
+        };
+        let thread = self.thread.iter_mut().flat_map(|thread| thread.messages.iter_mut());
+        let locals = self.messages.iter_mut()
+            .chain(thread)
+            .filter(|msg| msg.id.is_none() && msg.request_id == confirmed.request_id);
+        for local in locals {
+            local.id = confirmed.id;
+            local.timestamp = confirmed.timestamp;
+            local.seq = confirmed.seq;
+            local.thread = confirmed.thread;
+        };
+    }
 }
 
+/// Which field of the login form is being typed into
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum LoginField {
+    Username,
+    Password,
+}
+
+/// Our own user and the login form that is shown until the server confirms the username
 struct UserSetup {
     username: String,
+    password: String,
+    logged_in: bool,
+    field: LoginField,
+    guests_allowed: bool,
+    /// Last thing the server told us about the login, shown below the form
+    status: String,
+    status_is_error: bool,
 }
 
 impl UserSetup {
     fn new() -> UserSetup {
         UserSetup {
-            username: String::from("Anon"),
+            username: String::new(),
+            password: String::new(),
+            logged_in: false,
+            field: LoginField::Username,
+            guests_allowed: false,
+            status: String::from("Connecting..."),
+            status_is_error: false,
         }
     }
+
+
+    /// Edits the login form. Returns the message for the server once the user submits it
+    fn handle_key(&mut self, code: KeyCode) -> Option<Message> {
+        let field = match self.field {
+            LoginField::Username => &mut self.username,
+            LoginField::Password => &mut self.password,
+        };
+        let setup_type = match code {
+            KeyCode::Char(c) => {
+                field.push(c);
+                return None;
+            },
+            KeyCode::Backspace => {
+                field.pop();
+                return None;
+            },
+            KeyCode::Tab | KeyCode::Up | KeyCode::Down => {
+                self.field = match self.field {
+                    LoginField::Username => LoginField::Password,
+                    LoginField::Password => LoginField::Username,
+                };
+                return None;
+            },
+            KeyCode::Enter if self.field == LoginField::Username => {
+                self.field = LoginField::Password;
+                return None;
+            },
+            KeyCode::Enter => UserSetupType::Login {
+                username: self.username.clone(),
+                password: self.password.clone(),
+            },
+            KeyCode::F(2) => UserSetupType::Register {
+                username: self.username.clone(),
+                password: self.password.clone(),
+            },
+            KeyCode::F(3) if self.guests_allowed => UserSetupType::Guest {
+                username: self.username.clone(),
+            },
+            _ => return None,
+        };
+        self.status = String::from("Please wait...");
+        self.status_is_error = false;
+        Some(Message {
+            msg_type: MessageType::UserSetup(setup_type),
+            ..Message::new()
+        })
+    }
 }
 
 
@@ -79,7 +837,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Currently user input is handled inside the handle_ui function
     // So when the handle_ui tokio runtime terminates, the user caused the exit
-    ui_handle.await;
+    ui_handle.await?;
     Ok(())
 }
 
@@ -87,16 +845,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
 async fn poll(mut reader: BufReader<OwnedReadHalf>, tx: Sender<Message>) -> Result<(), &'static str> {
     loop {
         let mut line = String::new();
-        let poller = reader.read_line(&mut line);
-        pin_mut!(poller);
-        if let Ok(_) = tokio::time::timeout(Duration::from_micros(10), &mut poller).await {
-            if line.len() > 0 {
-                let deser_msg: Message = serde_json::from_str(line.trim()).unwrap();
-                tx.send(deser_msg).await.unwrap();
+        // Waiting for the whole line, as a line that was read halfway would be lost
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => return Err("the connection to the server was closed"),
+            Ok(_) => (),
+        };
+        if !line.trim().is_empty() {
+            // Show a line we can't read instead of crashing on it
+            let msg = Message::try_from(line.trim()).unwrap_or_else(|err| Message {
+                text: format!("Could not read a message from the server: {}", err),
+                msg_type: MessageType::Error,
+                ..Message::new()
+            });
+            if tx.send(msg).await.is_err() {
+                return Err("the UI has stopped");
             };
         };
     }
-    return Ok(());
 }
 
 
@@ -109,7 +874,17 @@ async fn handle_ui(mut incoming_reciever: Receiver<Message>, mut writer: BufWrit
     let mut terminal = Terminal::new(backend).unwrap();
     let mut app = App::default();
 
-    let res = terminal.draw(|f| draw_ui(&app, f));
+    // Every connection starts with telling the server what we speak
+    send_message(&mut writer, Message {
+        msg_type: MessageType::Hello {
+            version: PROTOCOL_VERSION,
+            client: format!("RustyChat client {}", env!("CARGO_PKG_VERSION")),
+            capabilities: CLIENT_CAPABILITIES.into_iter().collect(),
+        },
+        ..Message::new()
+    }).await;
+
+    let res = terminal.draw(|f| draw_login(setup, f));
     match res {
         Ok(_) => (),
         Err(err) => {
@@ -124,60 +899,288 @@ async fn handle_ui(mut incoming_reciever: Receiver<Message>, mut writer: BufWrit
         if crossterm::event::poll(Duration::from_micros(0)).unwrap()
         {
             if let Event::Key(key) = event::read().unwrap() {
-                match key.code {
-                    KeyCode::Char(c) => {
-                        app.input.push(c);
-                    },
-                    KeyCode::Backspace => {app.input.pop();},
-                    KeyCode::Enter => {
-                        if app.input == "/exit" {
-                            break;
-                        };
-                        if app.input.len() > 0 {
-                            let mut msg = Message {
-                                text: app.input.clone(),
-                                msg_type: MessageType::User,
-                                author: setup.username.clone(),
+                // Until we are logged in, all keys go to the login form
+                if !setup.logged_in {
+                    if key.code == KeyCode::Esc {
+                        break;
+                    };
+                    if let Some(msg) = setup.handle_key(key.code) {
+                        send_message(&mut writer, msg).await;
+                    };
+                } else if let (Some(search), true) = (&mut app.search, app.input.is_empty()) {
+                    // While the search results are open, the arrows move the selection
+                    match key.code {
+                        KeyCode::Up if search.context.is_none() => search.select_previous(),
+                        KeyCode::Down if search.context.is_none() => search.select_next(),
+                        KeyCode::Enter if search.context.is_none() => {
+                            if let Some(mut request) = search.jump_to_selected() {
+                                app.track_command(&mut request);
+                                send_message(&mut writer, request).await;
+                            };
+                        },
+                        KeyCode::Esc if search.context.is_some() => search.context = None,
+                        KeyCode::Esc => app.search = None,
+                        KeyCode::Char(c) => app.input.push(c),
+                        _ => (),
+                    };
+                } else {
+                    match key.code {
+                        KeyCode::Esc => {
+                            app.search = None;
+                            app.thread = None;
+                        },
+                        KeyCode::Char(c) => {
+                            app.input.push(c);
+                        },
+                        KeyCode::Backspace => {app.input.pop();},
+                        KeyCode::Up | KeyCode::PageUp => {
+                            let view_height = message_view_height(&terminal);
+                            let amount = if key.code == KeyCode::Up { 1 } else { view_height.max(1) };
+                            if let Some(mut request) = app.scroll_up(amount, view_height) {
+                                app.track_command(&mut request);
+                                send_message(&mut writer, request).await;
+                            };
+                        },
+                        KeyCode::Down => app.scroll_down(1),
+                        KeyCode::PageDown => app.scroll_down(message_view_height(&terminal).max(1)),
+                        KeyCode::End => app.scroll = 0,
+                        KeyCode::Enter => {
+                            if app.input == "/exit" {
+                                break;
                             };
-                            if app.input.starts_with("/") {
-                                msg.msg_type = MessageType::Command;
+                            // Reply to whoever sent us the last private message
+                            if let Some(reply) = app.input.strip_prefix("/r ") {
+                                match &app.last_dm_from {
+                                    Some(sender) => app.input = format!("/msg {} {}", sender, reply),
+                                    None => {
+                                        app.messages.push_back(Message {
+                                            text: String::from("Nobody has sent you a private message yet"),
+                                            msg_type: MessageType::SystemInfo,
+                                            ..Message::new()
+                                        });
+                                        app.input.clear();
+                                    },
+                                };
                             };
-                            app.messages.push_back(msg.clone());
-                            let deser : String = msg.into();
-                            writer.write(&deser.as_bytes()).await.unwrap();
-                            writer.write(&['\n' as u8]).await.unwrap();
-                            writer.flush().await.unwrap();
-                            app.input.clear();
-                        };
-                    },
-                    _ => (),
+                            if !app.input.is_empty() {
+                                // Edits, deletions and replies aren't commands, but messages of their own
+                                let request = app.send_request(&app.input.clone(), &setup.username)
+                                    .or_else(|| app.change_request(&app.input, &setup.username))
+                                    .or_else(|| app.reply_request(&app.input, &setup.username));
+                                let msg = request.unwrap_or_else(|| {
+                                    let msg_type = if app.input.starts_with('/') {
+                                        Command::parse(&app.input).map(MessageType::Command).map_err(|err| err.to_string())
+                                    } else {
+                                        Ok(MessageType::User)
+                                    };
+                                    msg_type.map(|msg_type| Message {
+                                        text: app.input.clone(),
+                                        msg_type,
+                                        author: setup.username.clone(),
+                                        room: app.current_room.clone(),
+                                        ..Message::new()
+                                    })
+                                });
+                                match msg {
+                                    Ok(mut msg) => {
+                                        match msg.msg_type {
+                                            MessageType::Command(_) => app.track_command(&mut msg),
+                                            MessageType::User => app.tag_message(&mut msg),
+                                            _ => (),
+                                        };
+                                        // The password only travels inside the command, not as the typed text
+                                        if let MessageType::Command(Command::Register { .. }) = msg.msg_type {
+                                            msg.text.clear();
+                                        };
+                                        // Where to save the file is up to us, the server doesn't need to know
+                                        if let MessageType::Command(Command::Accept { id, dest }) = &mut msg.msg_type {
+                                            app.downloads.insert(*id, Download { dest: dest.take(), assembly: None });
+                                        };
+                                        if let Some(echo) = local_echo(&msg) {
+                                            if let Some(thread) = &mut app.thread {
+                                                thread.add(&echo);
+                                            };
+                                            app.messages.push_back(echo);
+                                        };
+                                        send_message(&mut writer, msg).await;
+                                        app.input.clear();
+                                        // The next message gets announced right away
+                                        app.last_typing_notice = None;
+                                    },
+                                    // Keep the input, so the command can be fixed
+                                    Err(err) => app.messages.push_back(Message {
+                                        text: err,
+                                        msg_type: MessageType::Error,
+                                        ..Message::new()
+                                    }),
+                                };
+                            };
+                        },
+                        _ => (),
+                    };
                 };
+                // Let the room know we are typing, but not with every key
+                if setup.logged_in {
+                    if let Some(notice) = app.typing_notice() {
+                        send_message(&mut writer, notice).await;
+                    };
+                    // Scrolling down or closing a view may have shown new messages
+                    for receipt in app.receipt_updates() {
+                        send_message(&mut writer, receipt).await;
+                    };
+                    if let Some(status) = app.auto_away(true) {
+                        send_message(&mut writer, status).await;
+                    };
+                };
+            };
+        };
+
+        if setup.logged_in {
+            if let Some(status) = app.auto_away(false) {
+                send_message(&mut writer, status).await;
+            };
+            // One chunk at a time, so our chat messages don't have to wait for the whole file
+            if let Some(chunk) = app.uploads.first_mut().and_then(Upload::next_chunk) {
+                send_message(&mut writer, chunk).await;
             };
         };
 
         // Check if we can display any incoming messages on the UI
         let recv_incoming = incoming_reciever.recv();
         pin_mut!(recv_incoming);
-        if let Ok(x) = tokio::time::timeout(Duration::from_micros(wait_time), &mut recv_incoming).await {
-            if let Some(incoming_msg) = x {
-                if incoming_msg.author != setup.username {
-                    match incoming_msg.msg_type {
-                        MessageType::UserSetup(UserSetupType::UsernameConfirmed) => {
-                            setup.username = incoming_msg.text.clone();
-                        },
-                        _ => {
-                            app.messages.push_back(incoming_msg);
-                        }
-                    };
-                };
-                if app.messages.len() > 10 {
-                    app.messages.pop_front();
+        if let Ok(Some(incoming_msg)) = tokio::time::timeout(Duration::from_micros(wait_time), &mut recv_incoming).await {
+            if let MessageType::Edit { id } | MessageType::Delete { id }
+                | MessageType::ReactionUpdate { id, .. } | MessageType::ThreadUpdate { id, .. } = incoming_msg.msg_type {
+                app.apply_change(id, &incoming_msg);
+            } else if let MessageType::PresenceUpdate(status) = incoming_msg.msg_type {
+                // Our own status comes back this way as well
+                app.presence.insert(incoming_msg.author, status);
+            } else if !setup.logged_in || incoming_msg.author != setup.username {
+                match incoming_msg.msg_type {
+                    MessageType::Welcome { version, server, capabilities } => {
+                        match protocol::check_version(version) {
+                            Ok(_) => {
+                                setup.status = format!("Connected to {}", server);
+                                app.capabilities = protocol::negotiate_capabilities(&CLIENT_CAPABILITIES, &capabilities);
+                            },
+                            Err(err) => {
+                                setup.status = format!("{} can't be used with this client: {}", server, err);
+                                setup.status_is_error = true;
+                            },
+                        };
+                    },
+                    // Before logging in, errors (e.g. an incompatible version) belong on the login form
+                    MessageType::Error if !setup.logged_in => {
+                        setup.status = incoming_msg.text;
+                        setup.status_is_error = true;
+                    },
+                    MessageType::UserSetup(UserSetupType::LoginRequired { guests_allowed }) => {
+                        setup.guests_allowed = guests_allowed;
+                        setup.status = incoming_msg.text;
+                    },
+                    MessageType::UserSetup(UserSetupType::LoginFailed) => {
+                        setup.status = incoming_msg.text;
+                        setup.status_is_error = true;
+                    },
+                    MessageType::UserSetup(UserSetupType::Registered) => {
+                        setup.status = format!("Registered {}", incoming_msg.text);
+                        app.messages.push_back(Message {
+                            text: format!("The account {} is registered now", incoming_msg.text),
+                            msg_type: MessageType::SystemInfo,
+                            ..incoming_msg
+                        });
+                    },
+                    // Sent after logging in as well as after a /nick
+                    MessageType::UserSetup(UserSetupType::UsernameConfirmed) => {
+                        setup.logged_in = true;
+                        setup.password.clear();
+                        setup.username = incoming_msg.text.clone();
+                        app.username = incoming_msg.text.clone();
+                        app.messages.push_back(Message {
+                            text: format!("You are now known as {}", setup.username),
+                            msg_type: MessageType::SystemInfo,
+                            ..incoming_msg
+                        });
+                    },
+                    MessageType::ActiveRoom => {
+                        app.current_room = incoming_msg.room;
+                    },
+                    MessageType::Private => {
+                        app.last_dm_from = Some(incoming_msg.author.clone());
+                        app.messages.push_back(incoming_msg);
+                    },
+                    MessageType::HistoryBatch { messages, has_more } => {
+                        match &mut app.search {
+                            Some(search) if search.context_pending => {
+                                search.context_pending = false;
+                                search.context = Some(messages);
+                            },
+                            _ => app.prepend_history(incoming_msg.room, messages, has_more),
+                        };
+                    },
+                    MessageType::SearchResults { query, results } => {
+                        // The answer to /mentions looks like a search for ourselves
+                        if query.eq_ignore_ascii_case(&format!("@{}", app.username)) {
+                            app.unread_mentions = 0;
+                        };
+                        app.search = Some(SearchView::new(query, results));
+                    },
+                    MessageType::Receipts { receipts } => {
+                        if let Some(room) = incoming_msg.room {
+                            app.receipts.insert(room, receipts);
+                        };
+                    },
+                    MessageType::Typing => {
+                        app.typing.retain(|_, since| since.elapsed() < TYPING_TIMEOUT);
+                        if let Some(room) = incoming_msg.room {
+                            app.typing.insert((room, incoming_msg.author), Instant::now());
+                        };
+                    },
+                    MessageType::Thread { id, messages } => {
+                        app.search = None;
+                        app.thread = Some(ThreadView { root: id, messages });
+                    },
+                    MessageType::CommandResult { command, outcome } => {
+                        app.finish_command(incoming_msg.request_id, &command, outcome);
+                    },
+                    MessageType::UploadReady { .. } | MessageType::TransferDone { .. } | MessageType::TransferFailed { .. }
+                        | MessageType::DownloadStart { .. } | MessageType::FileChunk { .. } => {
+                        if let Some(notice) = app.handle_transfer(incoming_msg) {
+                            app.messages.push_back(notice);
+                        };
+                    },
+                    _ => {
+                        // Whoever sent the message is done typing it
+                        if let Some(room) = &incoming_msg.room {
+                            app.typing.remove(&(room.clone(), incoming_msg.author.clone()));
+                        };
+                        if let Some(thread) = &mut app.thread {
+                            thread.add(&incoming_msg);
+                        };
+                        if app.mentions_us(&incoming_msg) {
+                            app.add_mention();
+                        };
+                        app.messages.push_back(incoming_msg);
+                        // Keep the view where it is if the user scrolled up
+                        if app.scroll > 0 {
+                            app.scroll += 1;
+                        };
+                    }
                 };
+            } else {
+                app.confirm_own_message(incoming_msg);
+            };
+            for receipt in app.receipt_updates() {
+                send_message(&mut writer, receipt).await;
             };
         };
         
         // Match just in case something in the drawing goes wrong
-        let res = terminal.draw(|f| draw_ui(&app, f));
+        let res = terminal.draw(|f| if setup.logged_in {
+            draw_ui(&app, f)
+        } else {
+            draw_login(setup, f)
+        });
         match res {
             Ok(_) => (),
             Err(err) => {
@@ -199,6 +1202,60 @@ async fn handle_ui(mut incoming_reciever: Receiver<Message>, mut writer: BufWrit
 
 
 
+/// Writes a downloaded file to where the user wanted it: the destination itself, into it if it is a directory
+/// or into the current directory without one. Existing files are never overwritten
+fn save_download(dest: Option<&str>, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+    // The server checks the name as well, but it comes from another user after all
+    let name = transfer::file_name(Path::new(name)).unwrap_or_else(|| String::from("download"));
+    let path = match dest.map(PathBuf::from) {
+        Some(dest) if dest.is_dir() => dest.join(name),
+        Some(dest) => dest,
+        None => PathBuf::from(name),
+    };
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+    file.write_all(data)?;
+    Ok(path)
+}
+
+
+/// Amount of messages that fit into the message list
+fn message_view_height<B: Backend>(terminal: &Terminal<B>) -> usize {
+    // Everything but the title line, the input block and the borders of the list
+    terminal.size().map_or(0, |size| size.height.saturating_sub(1 + 3 + 2) as usize)
+}
+
+
+/// Serializes the message and writes it to the server
+async fn send_message(writer: &mut BufWriter<OwnedWriteHalf>, msg: Message) {
+    let deser : String = msg.into();
+    writer.write_all(deser.as_bytes()).await.unwrap();
+    writer.write_all(b"\n").await.unwrap();
+    writer.flush().await.unwrap();
+}
+
+
+/// What we show for a message we just sent ourselves.
+/// Private messages are shown like the ones we recieve instead of as the raw command.
+/// Edits and deletions only show once the server passes them back
+fn local_echo(msg: &Message) -> Option<Message> {
+    let echo = match &msg.msg_type {
+        MessageType::Edit { .. } | MessageType::Delete { .. } => return None,
+        // It would show the password
+        MessageType::Command(Command::Register { .. }) => return None,
+        MessageType::Command(Command::Msg { recipient, text }) => Message {
+            text: text.clone(),
+            msg_type: MessageType::Private,
+            author: msg.author.clone(),
+            recipient: Some(recipient.clone()),
+            request_id: msg.request_id,
+            ..Message::new()
+        },
+        _ => msg.clone(),
+    };
+    Some(echo)
+}
+
+
 fn draw_ui<B: Backend>(app: &App, f: &mut Frame<B>) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -212,42 +1269,48 @@ fn draw_ui<B: Backend>(app: &App, f: &mut Frame<B>) {
         )
         .split(f.size());
     
-    let msg = vec![
+    let mut msg = vec![
         Span::styled("RustyChat", Style::default().add_modifier(Modifier::BOLD)),
     ];
+    if let Some(room) = &app.current_room {
+        msg.push(Span::raw(format!(" - #{}", room)));
+    };
+    if let Some(status) = app.presence.get(&app.username).filter(|status| *status != &Status::default()) {
+        msg.push(Span::raw("   "));
+        msg.push(app.presence_dot(&app.username));
+        msg.push(Span::raw(status.to_string()));
+    };
+    if app.unread_mentions > 0 {
+        msg.push(Span::styled(
+            format!("   @{} (/mentions)", app.unread_mentions),
+            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        ));
+    };
+    for progress in app.transfer_progress() {
+        msg.push(Span::styled(format!("   {}", progress), Style::default().fg(Color::Cyan)));
+    };
+    if let Some(typing) = typing_status(&app.typing_in_current_room()) {
+        msg.push(Span::styled(format!("   {}", typing), Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC)));
+    };
     let style = Style::default();
     
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, chunks[0]);
-    
-
-    let messages: Vec<ListItem> = app
-    .messages
-    .iter()
-    .enumerate()
-    .map(|(i, msg)| {
-        let mut style = Style::default();
-        match msg.msg_type {
-            MessageType::SystemInfo => style = style.fg(Color::Red),
-            MessageType::User => (),
-            _ => (),
-        };
-        let span = Span::styled(format!("{}", msg), style);
-        let content = vec![Spans::from(span)];
-        ListItem::new(content)
-    })
-    .collect();
-    let messages =
-        List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-    f.render_widget(messages, chunks[1]);
 
+    if let Some(search) = &app.search {
+        draw_search(search, f, chunks[1]);
+    } else if let Some(thread) = &app.thread {
+        draw_thread(app, thread, f, chunks[1]);
+    } else {
+        draw_messages(app, f, chunks[1]);
+    };
 
     let input_block = chunks[2];
     let input = Paragraph::new(app.input.clone())
         .style(Style::default().fg(Color::Yellow))
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .block(Block::default().borders(Borders::ALL).title(input_title(app)));
     f.render_widget(input, input_block);
     f.set_cursor(
         // Put cursor past the end of the input text
@@ -255,4 +1318,289 @@ fn draw_ui<B: Backend>(app: &App, f: &mut Frame<B>) {
         // Move one line down, from the border to the input line
         input_block.y + 1,
     )
-}
\ No newline at end of file
+}
+
+
+/// Who is typing, for the status line
+fn typing_status(typing: &[&str]) -> Option<String> {
+    match typing {
+        [] => None,
+        [one] => Some(format!("{} is typing...", one)),
+        [one, other] => Some(format!("{} and {} are typing...", one, other)),
+        _ => Some(String::from("Several people are typing...")),
+    }
+}
+
+
+/// Title of the input block: the room we are talking in and the commands still waiting for an answer
+fn input_title(app: &App) -> String {
+    let mut title = match &app.current_room {
+        Some(room) => format!("Input (#{})", room),
+        None => String::from("Input"),
+    };
+    if !app.pending_commands.is_empty() {
+        let pending : Vec<&str> = app.pending_commands.values().map(String::as_str).collect();
+        title.push_str(&format!(" - waiting for {}", pending.join(", ")));
+    };
+    title
+}
+
+
+/// The messages of the rooms, scrolled to where the user wants them
+fn draw_messages<B: Backend>(app: &App, f: &mut Frame<B>, area: Rect) {
+    // Only show the part of the messages the user scrolled to
+    let view_height = area.height.saturating_sub(2) as usize;
+    let end = app.messages.len().saturating_sub(app.scroll).max(view_height.min(app.messages.len()));
+    let seen_by = app.seen_by();
+    let messages : Vec<ListItem> = fit_from_end(app.messages.range(..end), view_height)
+        .into_iter()
+        .map(|msg| message_item(app, msg, &seen_by))
+        .collect();
+    let title = if app.scroll > 0 {
+        format!("Messages (scrolled up {}, End to jump back)", app.scroll)
+    } else {
+        String::from("Messages")
+    };
+    let messages =
+        List::new(messages).block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(messages, area);
+}
+
+
+/// A message opened with /thread and its replies, newest at the bottom
+fn draw_thread<B: Backend>(app: &App, thread: &ThreadView, f: &mut Frame<B>, area: Rect) {
+    let view_height = area.height.saturating_sub(2) as usize;
+    let seen_by = app.seen_by();
+    let items : Vec<ListItem> = fit_from_end(thread.messages.iter(), view_height)
+        .into_iter()
+        .map(|msg| message_item(app, msg, &seen_by))
+        .collect();
+    let title = format!("Thread of [{}] (/reply {} <text> to answer, Esc to close)", thread.root, thread.root);
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(list, area);
+}
+
+
+/// The newest of the messages that fit into `height` lines, oldest first.
+/// Messages can take more than one line, so the lines are counted from the newest message up
+fn fit_from_end<'a>(messages: impl DoubleEndedIterator<Item = &'a Message>, height: usize) -> Vec<&'a Message> {
+    let mut lines = 0;
+    let mut fitting : Vec<&Message> = messages.rev()
+        .take_while(|msg| {
+            lines += message_height(msg);
+            lines <= height
+        })
+        .collect();
+    fitting.reverse();
+    fitting
+}
+
+
+/// How a single message is shown: a snippet of the message it replies to,
+/// the message itself with who has read up to it and the reactions to it
+fn message_item<'a>(app: &App, msg: &'a Message, seen_by: &BTreeMap<MessageId, Vec<&str>>) -> ListItem<'a> {
+    let mut style = Style::default();
+    let pending = msg.request_id.is_some_and(|id| app.pending_commands.contains_key(&id));
+    match msg.msg_type {
+        MessageType::SystemInfo => style = style.fg(Color::Red),
+        MessageType::Error => style = style.fg(Color::Red).add_modifier(Modifier::BOLD),
+        MessageType::Private => style = style.fg(Color::Magenta).add_modifier(Modifier::ITALIC),
+        MessageType::FileOffer { id: Some(_), .. } => style = style.fg(Color::Cyan),
+        MessageType::User => (),
+        _ => (),
+    };
+    if msg.deleted {
+        style = style.fg(Color::DarkGray).add_modifier(Modifier::ITALIC);
+    };
+    let mut content = Vec::new();
+    if let Some(parent) = msg.reply_to {
+        let quote = match app.find_message(parent) {
+            Some(parent) if parent.deleted => format!("{}: (message deleted)", parent.author),
+            Some(parent) => format!("{}: {}", parent.author, snippet(&parent.text)),
+            None => format!("reply to [{}]", parent),
+        };
+        content.push(Spans::from(Span::styled(
+            format!("{:8}> {}", "", quote),
+            Style::default().fg(Color::DarkGray),
+        )));
+    };
+    let mut text = format_with_time(msg, "%H:%M");
+    if pending {
+        style = style.fg(Color::DarkGray);
+        text.push_str(" (pending)");
+    };
+    // Only messages written by someone get the presence of their author
+    let dot = match msg.msg_type {
+        MessageType::User | MessageType::Private => app.presence_dot(&msg.author),
+        _ => Span::raw("  "),
+    };
+    let mut line = vec![dot];
+    line.extend(highlight_mentions(text, &app.username, style));
+    // The id is needed to /react or /reply to the message or to /delete it
+    if let (MessageType::User, Some(id), false) = (&msg.msg_type, msg.id, msg.deleted) {
+        line.push(Span::styled(format!("  [{}]", id), Style::default().fg(Color::DarkGray)));
+    };
+    if let Some(ticks) = app.delivery_ticks(msg) {
+        line.push(ticks);
+    };
+    if let Some(readers) = msg.id.and_then(|id| seen_by.get(&id)) {
+        line.push(Span::styled(format!("  seen by {}", readers.join(", ")), Style::default().fg(Color::DarkGray)));
+    };
+    if msg.reply_count > 0 {
+        let replies = if msg.reply_count == 1 { String::from("1 reply") } else { format!("{} replies", msg.reply_count) };
+        line.push(Span::styled(format!("  ({})", replies), Style::default().fg(Color::Cyan)));
+    };
+    content.push(Spans::from(line));
+    if !msg.reactions.is_empty() {
+        let reactions : Vec<String> = msg.reactions.iter()
+            .map(|(reaction, users)| format!("{} {}", reaction, users.len()))
+            .collect();
+        content.push(Spans::from(Span::styled(
+            format!("{:8}{}", "", reactions.join("  ")),
+            Style::default().fg(Color::Yellow),
+        )));
+    };
+    ListItem::new(content)
+}
+
+
+/// Splits the text into spans in the given style, with every `@username` of the user highlighted
+fn highlight_mentions(text: String, username: &str, style: Style) -> Vec<Span<'static>> {
+    let mention = format!("@{}", username.to_lowercase());
+    // Usernames are ASCII, so the lowercase text has the same byte positions
+    let lowercase = text.to_ascii_lowercase();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for (found, _) in lowercase.match_indices(&mention) {
+        let end = found + mention.len();
+        // "@ann" shouldn't be highlighted in "@anna" or "mail@ann"
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        let whole_name = !lowercase[end..].starts_with(is_name_char) && !lowercase[..found].ends_with(is_name_char);
+        if username.is_empty() || found < start || !whole_name {
+            continue;
+        };
+        spans.push(Span::styled(text[start..found].to_string(), style));
+        spans.push(Span::styled(text[found..end].to_string(), style.fg(Color::Black).bg(Color::Yellow)));
+        start = end;
+    };
+    spans.push(Span::styled(text[start..].to_string(), style));
+    spans
+}
+
+
+/// Lets the terminal ring its bell, e.g. when someone mentioned us
+fn ring_bell() {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(b"\x07");
+    let _ = stdout.flush();
+}
+
+
+/// The start of a message that is quoted above the replies to it
+fn snippet(text: &str) -> String {
+    if text.chars().count() <= SNIPPET_LENGTH {
+        return text.to_string();
+    };
+    let start : String = text.chars().take(SNIPPET_LENGTH).collect();
+    format!("{}...", start)
+}
+
+
+/// Amount of lines the message takes in the message list
+fn message_height(msg: &Message) -> usize {
+    1 + usize::from(msg.reply_to.is_some()) + usize::from(!msg.reactions.is_empty())
+}
+
+
+/// The results of a /search, or the messages around one of them once the user jumped to it
+fn draw_search<B: Backend>(search: &SearchView, f: &mut Frame<B>, area: Rect) {
+    let selected_id = search.results.get(search.selected).and_then(|result| result.id);
+    let (items, title, selected) = match &search.context {
+        Some(context) => {
+            let items : Vec<ListItem> = context.iter().map(|msg| ListItem::new(format_with_time(msg, "%Y-%m-%d %H:%M"))).collect();
+            let selected = context.iter().position(|msg| msg.id.is_some() && msg.id == selected_id);
+            (items, String::from("Context (Esc to go back to the results)"), selected)
+        },
+        None => {
+            let items : Vec<ListItem> = search.results.iter().map(|msg| ListItem::new(format_with_time(msg, "%Y-%m-%d %H:%M"))).collect();
+            let title = format!(
+                "{} result(s) for \"{}\" (Up/Down to select, Enter to jump to it, Esc to close)",
+                search.results.len(), search.query,
+            );
+            let selected = if search.results.is_empty() { None } else { Some(search.selected) };
+            (items, title, selected)
+        },
+    };
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default();
+    state.select(selected);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+
+/// The message with the (local) time the server got it in front.
+/// Messages we made up ourselves or that aren't confirmed yet don't have a time
+fn format_with_time(msg: &Message, time_format: &str) -> String {
+    match msg.timestamp {
+        Some(timestamp) => format!("{} {}", timestamp.with_timezone(&Local).format(time_format), msg),
+        None => format!("{:width$} {}", "", msg, width = Utc::now().format(time_format).to_string().len()),
+    }
+}
+
+
+/// Shows the login form until the server has confirmed our username
+fn draw_login<B: Backend>(setup: &UserSetup, f: &mut Frame<B>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Length(3),
+                Constraint::Length(3),
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Min(0),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    let title = Paragraph::new(Span::styled("RustyChat - Login", Style::default().add_modifier(Modifier::BOLD)));
+    f.render_widget(title, chunks[0]);
+
+    let field_style = |field: LoginField| if setup.field == field {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    let username = Paragraph::new(setup.username.clone())
+        .style(field_style(LoginField::Username))
+        .block(Block::default().borders(Borders::ALL).title("Username"));
+    f.render_widget(username, chunks[1]);
+    // Never show the password itself
+    let password = Paragraph::new("*".repeat(setup.password.chars().count()))
+        .style(field_style(LoginField::Password))
+        .block(Block::default().borders(Borders::ALL).title("Password"));
+    f.render_widget(password, chunks[2]);
+
+    let mut keys = String::from("Enter: log in | F2: register | Tab: switch field | Esc: quit");
+    if setup.guests_allowed {
+        keys.push_str(" | F3: join as guest (no password needed)");
+    };
+    f.render_widget(Paragraph::new(keys), chunks[3]);
+
+    let status_style = if setup.status_is_error {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default()
+    };
+    f.render_widget(Paragraph::new(Span::styled(setup.status.clone(), status_style)), chunks[4]);
+
+    let (block, len) = match setup.field {
+        LoginField::Username => (chunks[1], setup.username.chars().count()),
+        LoginField::Password => (chunks[2], setup.password.chars().count()),
+    };
+    f.set_cursor(block.x + len as u16 + 1, block.y + 1);
+}
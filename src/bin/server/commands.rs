@@ -0,0 +1,1005 @@
+use async_trait::async_trait;
+use tokio::sync::{Mutex, MutexGuard};
+
+use std::sync::Arc;
+
+use rusty_chat::{
+    shared::{Shared, MessageError, RoomError},
+    peer::{Peer, User, MAX_STATUS_LEN},
+    message::Message,
+    types::{MessageType, UserSetupType, RoomId},
+    transfer,
+    accounts,
+    reactions,
+    command::{self, Command, CommandError, CommandOutcome, CommandPayload, HistoryPosition},
+    permissions::{Permission, Role},
+    protocol::{ProtocolError, Capability},
+};
+
+use super::{SYSTEM_USRNAME, send_system_message, system_message, send_all, send_active_room, history_replay, announce_join};
+use super::transfers;
+
+/// Amount of messages /history returns if no count is given
+const DEFAULT_HISTORY_BATCH : usize = 20;
+const MAX_HISTORY_BATCH : usize = 100;
+/// Maximum amount of messages /search returns
+const MAX_SEARCH_RESULTS : usize = 20;
+/// Maximum amount of messages /mentions returns
+const MAX_MENTIONS : usize = 50;
+
+
+/// Everything a command gets to work with: the shared state and the user (and connection) that used it
+pub struct CommandContext<'a> {
+    pub state: Arc<Mutex<Shared>>,
+    pub user: &'a mut User,
+    pub peer: &'a mut Peer,
+    pub registry: &'a CommandRegistry,
+}
+
+
+/// A command users can type. Every command is registered once in the `CommandRegistry`,
+/// which hands it the commands with its name
+#[async_trait]
+pub trait ChatCommand: Send + Sync {
+    /// Name the command is typed as, without the '/'
+    fn name(&self) -> &'static str;
+
+    /// Other names the command can be typed as
+    fn aliases(&self) -> Vec<&'static str> {
+        Command::aliases(self.name())
+    }
+
+    /// How the command is used. Commands the client doesn't know have to tell themselves
+    fn usage(&self) -> &'static str {
+        Command::usage(self.name()).unwrap_or_default()
+    }
+
+    /// What the command does in a few words, shown by /help
+    fn description(&self) -> &'static str;
+
+    /// What the user needs to be allowed to use the command, in their current room.
+    /// Checked before the command runs, commands without one can be used by everyone
+    fn permission(&self) -> Option<Permission> {
+        None
+    }
+
+    /// Whether the command is about a room it finds itself, like the one of a message, instead of the current one.
+    /// The permission isn't checked before those run, they have to check it in that room themselves
+    fn resolves_room(&self) -> bool {
+        false
+    }
+
+    /// Carries out the command. Whatever can go wrong with it is reported as `CommandOutcome::Failed`,
+    /// the error is only for when the connection itself broke
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError>;
+
+    /// The outcome of using the command the wrong way
+    fn usage_error(&self) -> CommandOutcome {
+        CommandOutcome::Failed(CommandError::Usage(self.usage()).to_string())
+    }
+}
+
+
+/// All commands the server knows, built once at startup
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<Box<dyn ChatCommand>>,
+}
+
+impl CommandRegistry {
+    /// The registry with every command the server comes with
+    pub fn with_builtin_commands() -> CommandRegistry {
+        let mut registry = CommandRegistry::default();
+        registry.register(Create);
+        registry.register(Join);
+        registry.register(Leave);
+        registry.register(Switch);
+        registry.register(Msg);
+        registry.register(Nick);
+        registry.register(Register);
+        registry.register(History);
+        registry.register(Search);
+        registry.register(Rooms);
+        registry.register(React);
+        registry.register(Thread);
+        registry.register(Status);
+        registry.register(Mentions);
+        registry.register(SendFile);
+        registry.register(Accept);
+        registry.register(Kick);
+        registry.register(Ban);
+        registry.register(Unban);
+        registry.register(Mute);
+        registry.register(Unmute);
+        registry.register(Op);
+        registry.register(SetRole);
+        registry.register(ReadOnly);
+        registry.register(Help);
+        registry
+    }
+
+
+    pub fn register(&mut self, command: impl ChatCommand + 'static) {
+        self.commands.push(Box::new(command));
+    }
+
+
+    /// Finds the command by its name or one of its aliases
+    pub fn find(&self, name: &str) -> Option<&dyn ChatCommand> {
+        self.commands.iter()
+            .find(|command| command.name() == name || command.aliases().contains(&name))
+            .map(|command| command.as_ref())
+    }
+
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn ChatCommand> {
+        self.commands.iter().map(|command| command.as_ref())
+    }
+}
+
+
+struct Create;
+
+#[async_trait]
+impl ChatCommand for Create {
+    fn name(&self) -> &'static str { "create" }
+    fn description(&self) -> &'static str { "Creates a new room and joins it" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::CreateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Create { room, topic } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        match state_lock.create_chat(ctx.user, &room, topic) {
+            Ok(room) => {
+                let room_name = state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default();
+                println!("{} created #{}", ctx.user.usrname, room_name);
+                // The room might have existed with the same name before
+                let mut greeting = history_replay(&state_lock, &room_name, ctx.peer);
+                greeting.push(system_message(format!("Created room #{}", room_name)));
+                send_active_room(&mut state_lock, ctx.user.id);
+                state_lock.send_room_presence(room, ctx.user.id);
+                std::mem::drop(state_lock);
+                send_all(&mut ctx.peer.codec, greeting).await?;
+                Ok(CommandOutcome::Done(CommandPayload::Room(room_name)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Join;
+
+#[async_trait]
+impl ChatCommand for Join {
+    fn name(&self) -> &'static str { "join" }
+    fn description(&self) -> &'static str { "Joins a room, or the room another user is in" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::JoinRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let mut state_lock = ctx.state.lock().await;
+        let room = match command {
+            // Rooms are addressed by their name
+            Command::Join { room } => {
+                match state_lock.join_chat_by_name(ctx.user, &room) {
+                    Ok(room) => room,
+                    Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+                }
+            },
+            // Shortcut to get into the chat of another user
+            Command::JoinUser { username } => {
+                let room = match state_lock.join_chat(ctx.user, &username) {
+                    Ok(room) => room,
+                    Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+                };
+                // The friend might have just been put into a new room as well
+                if let Some(friend) = state_lock.get_usr_from_name(username).map(|friend| friend.id) {
+                    if state_lock.get_active_room(friend) == Some(room) {
+                        send_active_room(&mut state_lock, friend);
+                    };
+                };
+                room
+            },
+            _ => return Ok(self.usage_error()),
+        };
+        let greeting = announce_join(&mut state_lock, room, ctx.user, ctx.peer);
+        let room_name = state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default();
+        std::mem::drop(state_lock);
+        send_all(&mut ctx.peer.codec, greeting).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Room(room_name)))
+    }
+}
+
+
+struct Leave;
+
+#[async_trait]
+impl ChatCommand for Leave {
+    fn name(&self) -> &'static str { "leave" }
+    fn description(&self) -> &'static str { "Leaves a room" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Leave { room } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        match state_lock.leave_chat(ctx.user, &room) {
+            Ok(chat) => {
+                let msg = Message {
+                    text: format!("{} has left #{}", ctx.user.usrname, chat.name),
+                    msg_type: MessageType::SystemInfo,
+                    author: String::from(SYSTEM_USRNAME),
+                    ..Message::new()
+                };
+                println!("{}", msg);
+                state_lock.send_to_chat(chat.id, &msg, None);
+                send_active_room(&mut state_lock, ctx.user.id);
+                std::mem::drop(state_lock);
+                send_system_message(&mut ctx.peer.codec, format!("You have left #{}", chat.name)).await?;
+                Ok(CommandOutcome::Done(CommandPayload::Room(chat.name)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Switch;
+
+#[async_trait]
+impl ChatCommand for Switch {
+    fn name(&self) -> &'static str { "switch" }
+    fn description(&self) -> &'static str { "Changes the room your messages go to" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Switch { room } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        match state_lock.switch_chat(ctx.user, &room) {
+            Ok(room) => {
+                send_active_room(&mut state_lock, ctx.user.id);
+                let room_name = state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default();
+                Ok(CommandOutcome::Done(CommandPayload::Room(room_name)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Msg;
+
+#[async_trait]
+impl ChatCommand for Msg {
+    fn name(&self) -> &'static str { "msg" }
+    fn description(&self) -> &'static str { "Sends a private message" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::SendPrivateMessages) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let (recipient, text) = match command {
+            Command::Msg { recipient, text } if !recipient.is_empty() && !text.trim().is_empty() => (recipient, text),
+            _ => return Ok(self.usage_error()),
+        };
+        let msg = Message {
+            text,
+            msg_type: MessageType::Private,
+            author: ctx.user.usrname.clone(),
+            recipient: Some(recipient.clone()),
+            ..Message::new()
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let recipient_id = state_lock.get_usr_from_name(recipient.clone()).map(|other| other.id);
+        if !recipient_id.is_some_and(|other| state_lock.send_to_user(other, &msg)) {
+            return Ok(CommandOutcome::Failed(format!("{} is not online", recipient)));
+        };
+        Ok(CommandOutcome::Done(CommandPayload::Username(recipient)))
+    }
+}
+
+
+struct Nick;
+
+#[async_trait]
+impl ChatCommand for Nick {
+    fn name(&self) -> &'static str { "nick" }
+    fn description(&self) -> &'static str { "Changes your username" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ChangeNick) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Nick { username } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        match state_lock.rename_peer(ctx.user, &username) {
+            Ok(renamed) => {
+                let msg = Message {
+                    text: format!("{} is now known as {}", ctx.user.usrname, renamed.usrname),
+                    msg_type: MessageType::SystemInfo,
+                    author: String::from(SYSTEM_USRNAME),
+                    ..Message::new()
+                };
+                println!("{}", msg);
+                let rooms : Vec<RoomId> = state_lock.get_chats_from_user(ctx.user.id).iter().map(|chat| chat.id).collect();
+                for room in rooms {
+                    state_lock.send_to_chat(room, &msg, Some(ctx.user.id));
+                };
+                *ctx.user = renamed;
+                // Presence is shown by username, so everyone has to learn it again under the new one
+                if let Some(status) = state_lock.peers.get(&ctx.user.id).map(|peer| peer.status.clone()) {
+                    state_lock.send_presence(ctx.user.id, &status);
+                };
+                std::mem::drop(state_lock);
+                ctx.peer.codec.send_message(&Message {
+                    text: ctx.user.usrname.clone(),
+                    msg_type: MessageType::UserSetup(UserSetupType::UsernameConfirmed),
+                    author: String::from(SYSTEM_USRNAME),
+                    ..Message::new()
+                }).await?;
+                Ok(CommandOutcome::Done(CommandPayload::Username(ctx.user.usrname.clone())))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Register;
+
+#[async_trait]
+impl ChatCommand for Register {
+    fn name(&self) -> &'static str { "register" }
+    fn description(&self) -> &'static str { "Turns your guest name into an account" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Register { password } = command else {
+            return Ok(self.usage_error());
+        };
+        if ctx.state.lock().await.accounts.get_by_id(ctx.user.id).is_some() {
+            return Ok(CommandOutcome::Failed(format!("{} is already registered", ctx.user.usrname)));
+        };
+        // Hashing takes a while, so the lock isn't held in the meantime
+        let password_hash = accounts::hash_password(&password);
+        let mut state_lock = ctx.state.lock().await;
+        // The guest keeps its id, which now belongs to the account
+        let registered = password_hash.and_then(|hash| {
+            state_lock.accounts.register(ctx.user.id, &ctx.user.usrname, hash).map(|_| ())
+        });
+        std::mem::drop(state_lock);
+        match registered {
+            Ok(_) => {
+                println!("Registered account {} ({})", ctx.user.usrname, ctx.user.id);
+                ctx.peer.codec.send_message(&Message {
+                    text: ctx.user.usrname.clone(),
+                    msg_type: MessageType::UserSetup(UserSetupType::Registered),
+                    author: String::from(SYSTEM_USRNAME),
+                    ..Message::new()
+                }).await?;
+                Ok(CommandOutcome::Done(CommandPayload::Username(ctx.user.usrname.clone())))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct History;
+
+#[async_trait]
+impl ChatCommand for History {
+    fn name(&self) -> &'static str { "history" }
+    fn description(&self) -> &'static str { "Shows older messages of the current (or given) room" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::History { count, position, room } = command else {
+            return Ok(self.usage_error());
+        };
+        if !ctx.peer.supports(Capability::History) {
+            return Ok(CommandOutcome::Failed(String::from("Your client doesn't support /history")));
+        };
+        let count = count.unwrap_or(DEFAULT_HISTORY_BATCH).clamp(1, MAX_HISTORY_BATCH);
+        let state_lock = ctx.state.lock().await;
+        let room_name = match state_lock.get_target_room(ctx.user.id, room.as_deref()) {
+            Ok(room) => state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default(),
+            Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+        };
+        let (messages, has_more) = match position {
+            Some(HistoryPosition::Around(id)) => {
+                let messages = state_lock.history.around(&room_name, id, count);
+                let has_more = messages.first().and_then(|message| message.id)
+                    .is_some_and(|first| !state_lock.history.before(&room_name, Some(first), 1).is_empty());
+                (messages, has_more)
+            },
+            Some(HistoryPosition::Before(id)) => state_lock.history.page_before(&room_name, Some(id), count),
+            None => state_lock.history.page_before(&room_name, None, count),
+        };
+        std::mem::drop(state_lock);
+        let found = messages.len();
+        ctx.peer.codec.send_message(&Message {
+            msg_type: MessageType::HistoryBatch { messages, has_more },
+            author: String::from(SYSTEM_USRNAME),
+            room: Some(room_name),
+            ..Message::new()
+        }).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Count(found)))
+    }
+}
+
+
+struct Search;
+
+#[async_trait]
+impl ChatCommand for Search {
+    fn name(&self) -> &'static str { "search" }
+    fn description(&self) -> &'static str { "Searches the messages of the rooms you are in" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        if !ctx.peer.supports(Capability::Search) {
+            return Ok(CommandOutcome::Failed(String::from("Your client doesn't support /search")));
+        };
+        let query = match command {
+            Command::Search(query) if query.terms.iter().any(|term| !term.trim().is_empty()) => query,
+            _ => return Ok(self.usage_error()),
+        };
+        let state_lock = ctx.state.lock().await;
+        // Only the rooms the user is in may be searched
+        let rooms : Vec<String> = match &query.room {
+            Some(name) => match state_lock.get_target_room(ctx.user.id, Some(name)) {
+                Ok(room) => state_lock.get_chat(room).map(|chat| chat.name.clone()).into_iter().collect(),
+                Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+            },
+            None => state_lock.get_chats_from_user(ctx.user.id).iter().map(|chat| chat.name.clone()).collect(),
+        };
+        let results = state_lock.history.search(&rooms, &query, MAX_SEARCH_RESULTS);
+        std::mem::drop(state_lock);
+        let found = results.len();
+        ctx.peer.codec.send_message(&Message {
+            text: format!("{} result(s) for \"{}\"", results.len(), query),
+            msg_type: MessageType::SearchResults { query: query.to_string(), results },
+            author: String::from(SYSTEM_USRNAME),
+            ..Message::new()
+        }).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Count(found)))
+    }
+}
+
+
+struct Mentions;
+
+#[async_trait]
+impl ChatCommand for Mentions {
+    fn name(&self) -> &'static str { "mentions" }
+    fn description(&self) -> &'static str { "Lists the newest messages that mention you and marks them as read" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        // They are shown like search results
+        if !ctx.peer.supports(Capability::Search) {
+            return Ok(CommandOutcome::Failed(String::from("Your client doesn't support /mentions")));
+        };
+        let Command::Mentions = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let rooms : Vec<String> = state_lock.get_chats_from_user(ctx.user.id).iter().map(|chat| chat.name.clone()).collect();
+        let results = state_lock.history.mentions(&rooms, &ctx.user.usrname, MAX_MENTIONS);
+        state_lock.clear_unread_mentions(ctx.user.id);
+        std::mem::drop(state_lock);
+        let found = results.len();
+        ctx.peer.codec.send_message(&Message {
+            text: format!("{} message(s) mention you", found),
+            msg_type: MessageType::SearchResults { query: format!("@{}", ctx.user.usrname), results },
+            author: String::from(SYSTEM_USRNAME),
+            ..Message::new()
+        }).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Count(found)))
+    }
+}
+
+
+struct SendFile;
+
+#[async_trait]
+impl ChatCommand for SendFile {
+    fn name(&self) -> &'static str { "send" }
+    fn description(&self) -> &'static str { "Offers a file to a user or a room, the active one by default" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::SendFiles) }
+
+    async fn run(&self, _ctx: &mut CommandContext<'_>, _command: Command) -> Result<CommandOutcome, ProtocolError> {
+        // Only the client can read the file, clients that support it send a FileOffer instead
+        Ok(CommandOutcome::Failed(String::from("Your client doesn't support sending files")))
+    }
+}
+
+
+struct Accept;
+
+#[async_trait]
+impl ChatCommand for Accept {
+    fn name(&self) -> &'static str { "accept" }
+    fn description(&self) -> &'static str { "Downloads a file someone offered you, into the current directory by default" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        if !ctx.peer.supports(Capability::FileTransfer) {
+            return Ok(CommandOutcome::Failed(String::from("Your client doesn't support receiving files")));
+        };
+        let Command::Accept { id, .. } = command else {
+            return Ok(self.usage_error());
+        };
+        let state_lock = ctx.state.lock().await;
+        let (file, data) = match state_lock.downloadable_file(ctx.user.id, id) {
+            Ok(download) => download,
+            Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+        };
+        let transmitter = match state_lock.peers.get(&ctx.user.id) {
+            Some(peer) => peer.transmitter.clone(),
+            None => return Ok(CommandOutcome::Failed(String::from("You are not connected"))),
+        };
+        std::mem::drop(state_lock);
+        println!("{} is downloading transfer {}", ctx.user.usrname, id);
+        ctx.peer.codec.send_message(&Message {
+            text: format!("Downloading {} ({})", file.name, transfer::format_size(file.size)),
+            msg_type: MessageType::DownloadStart { id, file },
+            author: String::from(SYSTEM_USRNAME),
+            ..Message::new()
+        }).await?;
+        tokio::spawn(transfers::stream_file(transmitter, id, data));
+        Ok(CommandOutcome::Done(CommandPayload::Empty))
+    }
+}
+
+
+struct Kick;
+
+#[async_trait]
+impl ChatCommand for Kick {
+    fn name(&self) -> &'static str { "kick" }
+    fn description(&self) -> &'static str { "Removes someone from the current room. Needs to be its owner or an operator" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Kick { username } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let kicked = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.kick(ctx.user.id, room, &username)?)));
+        match kicked {
+            Ok((room, kicked)) => {
+                let text = format!("{} was kicked by {}", kicked.usrname, ctx.user.usrname);
+                announce_moderation(&mut state_lock, room, text, Some(&kicked));
+                Ok(CommandOutcome::Done(CommandPayload::Username(kicked.usrname)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Ban;
+
+#[async_trait]
+impl ChatCommand for Ban {
+    fn name(&self) -> &'static str { "ban" }
+    fn description(&self) -> &'static str { "Keeps someone out of the current room, with 'address' everyone connecting from their address too" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Ban { username, address } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let banned = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.ban(ctx.user.id, room, &username, address)?)));
+        match banned {
+            Ok((room, removed)) => {
+                let by_address = if address { " (and their address)" } else { "" };
+                let text = format!("{}{} was banned by {}", username, by_address, ctx.user.usrname);
+                announce_moderation(&mut state_lock, room, text, removed.as_ref());
+                Ok(CommandOutcome::Done(CommandPayload::Username(username)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Unban;
+
+#[async_trait]
+impl ChatCommand for Unban {
+    fn name(&self) -> &'static str { "unban" }
+    fn description(&self) -> &'static str { "Lets someone who was banned into the current room again" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Unban { username } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let unbanned = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| state_lock.unban(ctx.user.id, room, &username).map(|_| room));
+        match unbanned {
+            Ok(room) => {
+                let text = format!("{} was unbanned by {}", username, ctx.user.usrname);
+                announce_moderation(&mut state_lock, room, text, None);
+                Ok(CommandOutcome::Done(CommandPayload::Username(username)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Mute;
+
+#[async_trait]
+impl ChatCommand for Mute {
+    fn name(&self) -> &'static str { "mute" }
+    fn description(&self) -> &'static str { "Stops someone from sending messages to the current room, for a while or until unmuted" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Mute { username, duration } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let muted = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.mute(ctx.user.id, room, &username, duration)?)));
+        match muted {
+            Ok((room, muted)) => {
+                let text = match duration {
+                    Some(duration) => format!("{} was muted for {} by {}", muted.usrname, command::format_duration(duration), ctx.user.usrname),
+                    None => format!("{} was muted by {}", muted.usrname, ctx.user.usrname),
+                };
+                announce_moderation(&mut state_lock, room, text, None);
+                Ok(CommandOutcome::Done(CommandPayload::Username(muted.usrname)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Unmute;
+
+#[async_trait]
+impl ChatCommand for Unmute {
+    fn name(&self) -> &'static str { "unmute" }
+    fn description(&self) -> &'static str { "Lets someone who was muted send messages to the current room again" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Unmute { username } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let unmuted = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.unmute(ctx.user.id, room, &username)?)));
+        match unmuted {
+            Ok((room, unmuted)) => {
+                let text = format!("{} was unmuted by {}", unmuted.usrname, ctx.user.usrname);
+                announce_moderation(&mut state_lock, room, text, None);
+                Ok(CommandOutcome::Done(CommandPayload::Username(unmuted.usrname)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Op;
+
+#[async_trait]
+impl ChatCommand for Op {
+    fn name(&self) -> &'static str { "op" }
+    fn description(&self) -> &'static str { "Makes someone an operator of the current room, or takes it back. Needs to be its owner" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ModerateRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Op { username } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let toggled = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.toggle_operator(ctx.user.id, room, &username)?)));
+        match toggled {
+            Ok((room, (user, operator))) => {
+                let text = if operator {
+                    format!("{} made {} an operator", ctx.user.usrname, user.usrname)
+                } else {
+                    format!("{} removed {} from the operators", ctx.user.usrname, user.usrname)
+                };
+                announce_moderation(&mut state_lock, room, text, None);
+                Ok(CommandOutcome::Done(CommandPayload::Username(user.usrname)))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct SetRole;
+
+#[async_trait]
+impl ChatCommand for SetRole {
+    fn name(&self) -> &'static str { "role" }
+    fn description(&self) -> &'static str { "Gives someone a role on the server, or only in a room. 'default' takes it back" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Role { username, role, room } = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let room = match room.map(|room| state_lock.get_chat_from_name(&room).map(|chat| chat.id).ok_or(RoomError::NotFound(room))) {
+            Some(Ok(room)) => Some(room),
+            Some(Err(err)) => return Ok(CommandOutcome::Failed(err.to_string())),
+            None => None,
+        };
+        let changed = match room {
+            Some(room) => state_lock.set_room_role(ctx.user.id, room, &username, role),
+            None => state_lock.set_server_role(ctx.user.id, &username, role),
+        };
+        let role = match changed {
+            Ok(role) => role,
+            Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+        };
+        match room {
+            Some(room) => {
+                let text = format!("{} made {} {} in this room", ctx.user.usrname, username, role.with_article());
+                announce_moderation(&mut state_lock, room, text, None);
+            },
+            None => {
+                println!("{} made {} a {}", ctx.user.usrname, username, role);
+                if let Some(user) = state_lock.get_usr_from_name(username.clone()).map(|user| user.id) {
+                    state_lock.send_to_user(user, &Message {
+                        text: format!("{} made you {}", ctx.user.usrname, role.with_article()),
+                        msg_type: MessageType::SystemInfo,
+                        author: String::from(SYSTEM_USRNAME),
+                        ..Message::new()
+                    });
+                };
+                std::mem::drop(state_lock);
+                send_system_message(&mut ctx.peer.codec, format!("{} is {} now", username, role.with_article())).await?;
+            },
+        };
+        Ok(CommandOutcome::Done(CommandPayload::Username(username)))
+    }
+}
+
+
+struct ReadOnly;
+
+#[async_trait]
+impl ChatCommand for ReadOnly {
+    fn name(&self) -> &'static str { "readonly" }
+    fn description(&self) -> &'static str { "Turns the current room into an announcement room only moderators can post in, or back" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::ManageRooms) }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::ReadOnly = command else {
+            return Ok(self.usage_error());
+        };
+        let mut state_lock = ctx.state.lock().await;
+        let toggled = state_lock.get_target_room(ctx.user.id, None)
+            .and_then(|room| Ok((room, state_lock.toggle_read_only(ctx.user.id, room)?)));
+        match toggled {
+            Ok((room, read_only)) => {
+                let text = if read_only {
+                    let required = state_lock.config.required_role(Permission::PostAnnouncements);
+                    format!("{} made the room read-only, only {} or higher can post now", ctx.user.usrname, required.with_article())
+                } else {
+                    format!("{} made the room open to everyone again", ctx.user.usrname)
+                };
+                announce_moderation(&mut state_lock, room, text, None);
+                Ok(CommandOutcome::Done(CommandPayload::Empty))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+/// Tells the room what a moderator did. A user who was removed from the room hears about it as well
+/// and learns which room their messages go to now
+fn announce_moderation(state_lock: &mut MutexGuard<'_, Shared>, room: RoomId, text: String, removed: Option<&User>) {
+    println!("{}", text);
+    let msg = Message {
+        text,
+        msg_type: MessageType::SystemInfo,
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    };
+    state_lock.send_to_chat(room, &msg, None);
+    if let Some(removed) = removed {
+        let room_name = state_lock.get_chat(room).map(|chat| chat.name.clone());
+        state_lock.send_to_user(removed.id, &Message { room: room_name, ..msg });
+        send_active_room(state_lock, removed.id);
+    };
+}
+
+
+struct Rooms;
+
+#[async_trait]
+impl ChatCommand for Rooms {
+    fn name(&self) -> &'static str { "rooms" }
+    fn description(&self) -> &'static str { "Lists all rooms" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, _command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let state_lock = ctx.state.lock().await;
+        let text = if state_lock.chats.is_empty() {
+            String::from("There are no rooms yet. Type /create #<room> to create one.")
+        } else {
+            let rooms : Vec<String> = state_lock.chats.iter().map(|chat| chat.to_string()).collect();
+            format!("Rooms: {}", rooms.join(", "))
+        };
+        let names = state_lock.chats.iter().map(|chat| chat.name.clone()).collect();
+        std::mem::drop(state_lock);
+        send_system_message(&mut ctx.peer.codec, text).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Rooms(names)))
+    }
+}
+
+
+struct React;
+
+#[async_trait]
+impl ChatCommand for React {
+    fn name(&self) -> &'static str { "react" }
+    fn description(&self) -> &'static str { "Reacts to a message with an emoji, or takes the reaction back" }
+    fn permission(&self) -> Option<Permission> { Some(Permission::React) }
+    fn resolves_room(&self) -> bool { true }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::React { id, reaction } = command else {
+            return Ok(self.usage_error());
+        };
+        let reaction = match reactions::parse_reaction(&reaction) {
+            Ok(reaction) => reaction,
+            Err(err) => return Ok(CommandOutcome::Failed(err.to_string())),
+        };
+        let mut state_lock = ctx.state.lock().await;
+        // Only messages of rooms the user is in can be reacted to
+        let room = match state_lock.find_message_room(ctx.user.id, id) {
+            Some(room) => room,
+            None => return Ok(CommandOutcome::Failed(MessageError::NotFound(id).to_string())),
+        };
+        if let Err(err) = state_lock.check_permission(ctx.user.id, Some(room), Permission::React) {
+            return Ok(CommandOutcome::Failed(err.to_string()));
+        };
+        // Reactions reach the whole room like messages do, so muted users and read-only rooms hold them back as well
+        if let Err(err) = state_lock.check_not_muted(ctx.user.id, room) {
+            return Ok(CommandOutcome::Failed(err.to_string()));
+        };
+        if state_lock.get_chat(room).is_some_and(|chat| chat.read_only) {
+            if let Err(err) = state_lock.check_permission(ctx.user.id, Some(room), Permission::PostAnnouncements) {
+                return Ok(CommandOutcome::Failed(err.to_string()));
+            };
+        };
+        match state_lock.toggle_reaction(ctx.user.id, room, id, reaction) {
+            Ok(message) => {
+                state_lock.send_to_chat(room, &Message {
+                    msg_type: MessageType::ReactionUpdate { id, reactions: message.reactions },
+                    author: ctx.user.usrname.clone(),
+                    ..Message::new()
+                }, None);
+                Ok(CommandOutcome::Done(CommandPayload::Empty))
+            },
+            Err(err) => Ok(CommandOutcome::Failed(err.to_string())),
+        }
+    }
+}
+
+
+struct Thread;
+
+#[async_trait]
+impl ChatCommand for Thread {
+    fn name(&self) -> &'static str { "thread" }
+    fn description(&self) -> &'static str { "Shows a message with all replies to it" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Thread { id } = command else {
+            return Ok(self.usage_error());
+        };
+        let state_lock = ctx.state.lock().await;
+        let room = match state_lock.find_message_room(ctx.user.id, id) {
+            Some(room) => room,
+            None => return Ok(CommandOutcome::Failed(MessageError::NotFound(id).to_string())),
+        };
+        let room_name = state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default();
+        // A reply shows the whole thread it is part of
+        let root = state_lock.history.get(&room_name, id).and_then(|message| message.thread).unwrap_or(id);
+        let messages = state_lock.history.thread(&room_name, root);
+        std::mem::drop(state_lock);
+        let replies = messages.len().saturating_sub(1);
+        ctx.peer.codec.send_message(&Message {
+            msg_type: MessageType::Thread { id: root, messages },
+            author: String::from(SYSTEM_USRNAME),
+            room: Some(room_name),
+            ..Message::new()
+        }).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Count(replies)))
+    }
+}
+
+
+struct Status;
+
+#[async_trait]
+impl ChatCommand for Status {
+    fn name(&self) -> &'static str { "status" }
+    fn description(&self) -> &'static str { "Tells the people in your rooms whether you are around, optionally with a text" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Status { presence, text } = command else {
+            return Ok(self.usage_error());
+        };
+        if text.as_ref().is_some_and(|text| text.chars().count() > MAX_STATUS_LEN) {
+            return Ok(CommandOutcome::Failed(format!("The status text can't be longer than {} characters", MAX_STATUS_LEN)));
+        };
+        let mut state_lock = ctx.state.lock().await;
+        match state_lock.set_status(ctx.user.id, presence, text) {
+            Some(status) => {
+                println!("{} is {}", ctx.user.usrname, status);
+                Ok(CommandOutcome::Done(CommandPayload::Empty))
+            },
+            None => Ok(CommandOutcome::Failed(String::from("You are not connected"))),
+        }
+    }
+}
+
+
+struct Help;
+
+#[async_trait]
+impl ChatCommand for Help {
+    fn name(&self) -> &'static str { "help" }
+    fn description(&self) -> &'static str { "Lists all commands or explains one of them" }
+
+    async fn run(&self, ctx: &mut CommandContext<'_>, command: Command) -> Result<CommandOutcome, ProtocolError> {
+        let Command::Help { command: name } = command else {
+            return Ok(self.usage_error());
+        };
+        let name = match name {
+            Some(name) => name,
+            None => {
+                send_system_message(&mut ctx.peer.codec, String::from("Commands:")).await?;
+                for command in ctx.registry.iter() {
+                    send_system_message(&mut ctx.peer.codec, format!("{} - {}", command.usage(), command.description())).await?;
+                };
+                for (_, usage, description) in command::CLIENT_COMMANDS {
+                    send_system_message(&mut ctx.peer.codec, format!("{} - {}", usage, description)).await?;
+                };
+                send_system_message(&mut ctx.peer.codec, String::from("Type /help <command> for details. Type /exit to quit.")).await?;
+                return Ok(CommandOutcome::Done(CommandPayload::Empty));
+            },
+        };
+        let command = match ctx.registry.find(&name) {
+            Some(command) => command,
+            None => {
+                let Some((_, usage, description)) = command::CLIENT_COMMANDS.iter().find(|(command, ..)| *command == name) else {
+                    return Ok(CommandOutcome::Failed(format!("There is no command called /{}", name)));
+                };
+                send_system_message(&mut ctx.peer.codec, format!("{} - {}", usage, description)).await?;
+                return Ok(CommandOutcome::Done(CommandPayload::Empty));
+            },
+        };
+        let mut text = format!("{} - {}", command.usage(), command.description());
+        let aliases = command.aliases();
+        if !aliases.is_empty() {
+            let aliases : Vec<String> = aliases.iter().map(|alias| format!("/{}", alias)).collect();
+            text.push_str(&format!(". Also works as {}", aliases.join(", ")));
+        };
+        if let Some(permission) = command.permission() {
+            let required = ctx.state.lock().await.config.required_role(permission);
+            if required > Role::Guest {
+                text.push_str(&format!(". Needs to be {} or higher", required.with_article()));
+            };
+        };
+        send_system_message(&mut ctx.peer.codec, text).await?;
+        Ok(CommandOutcome::Done(CommandPayload::Empty))
+    }
+}
@@ -0,0 +1,614 @@
+use chrono::Utc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, MutexGuard};
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod commands;
+mod transfers;
+
+use commands::{CommandRegistry, CommandContext};
+
+use rusty_chat::{
+    Reciever,
+    shared::Shared,
+    peer::{Peer, User},
+    codec::Codec,
+    message::Message,
+    types::{MessageType, UserSetupType, RoomId, UserId, ConnectionId, RequestId, Reactions, Receipt, Presence, Status},
+    accounts::{self, AccountStore, AccountError},
+    config::{ServerConfig, DEFAULT_CONFIG_PATH},
+    history::{HistoryStore, MemoryHistory, FileHistory},
+    command::{Command, CommandError, CommandOutcome},
+    protocol::{self, ProtocolError, Capability},
+    permissions::PermissionError,
+};
+
+const SYSTEM_USRNAME : &str= "SYSTEM";
+/// How long a new connection has to send its Hello
+const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs(10);
+/// Everything this server can do, if the client supports it as well
+const SERVER_CAPABILITIES : [Capability; 6] = [
+    Capability::History,
+    Capability::Search,
+    Capability::Typing,
+    Capability::Receipts,
+    Capability::Presence,
+    Capability::FileTransfer,
+];
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // The config file can be passed as the first argument
+    let config_path = std::env::args().nth(1).unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH));
+    let config = ServerConfig::load(Path::new(&config_path))?;
+    let accounts = AccountStore::open(&config.accounts_path)?;
+    let history : Box<dyn HistoryStore> = match &config.history_path {
+        Some(path) => Box::new(FileHistory::open(path)?),
+        None => Box::new(MemoryHistory::new(config.history_capacity)),
+    };
+    let addr = config.addr.clone();
+    println!("Guests are {}", if config.allow_guests { "allowed" } else { "not allowed" });
+
+    let state = Arc::new(Mutex::new(Shared::with_config(config, accounts, history)));
+    let registry = Arc::new(CommandRegistry::with_builtin_commands());
+    let listener = TcpListener::bind(&addr).await?;
+
+    println!("Server running on {}", addr);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        let registry = Arc::clone(&registry);
+
+        tokio::spawn(async move {
+            println!("accepted connection");
+            if let Err(e) = process(state, registry, stream, addr).await {
+                eprintln!("Process; error = {:?}", e);
+            }
+        });
+    }
+}
+
+/// Processes the current client (represented by a TcpStream)
+async fn process(state: Arc<Mutex<Shared>>, registry: Arc<CommandRegistry>, stream: TcpStream, peer_addr: SocketAddr,) 
+    -> Result<(), Box<dyn Error>> 
+{
+    let mut codec = Codec::new(stream);
+
+    let mut state_lock = state.lock().await;
+    let conn_id = state_lock.next_connection_id();
+    std::mem::drop(state_lock);
+    println!("{} connected from {}", conn_id, peer_addr);
+
+    let capabilities = handshake(&mut codec, conn_id).await?;
+    let (mut user, reciever) = login(&state, &mut codec, conn_id, peer_addr, &capabilities).await?;
+    println!("{} logged in as {} ({})", conn_id, user.usrname, user.id);
+    let mut peer = Peer::new(codec, reciever, capabilities);
+    let unread_mentions = state.lock().await.unread_mentions(user.id);
+    if let Err(err) = greet(&mut peer, &user, unread_mentions).await {
+        eprintln!("Could not greet {}: {}", user.usrname, err);
+    };
+
+    loop {
+        let result = tokio::select! {
+            Some(line) = peer.reciever.recv() => peer.codec.send_line(line).await,
+            result = peer.codec.next_message() => match result {
+                Some(Ok(msg)) => handle_message(state.clone(), msg, &mut user, &mut peer, &registry).await,
+                // A single bad line doesn't end the connection, the client just gets told about it
+                Some(Err(err)) if err.is_recoverable() => send_error(&mut peer.codec, &err).await,
+                Some(Err(err)) => {
+                    // Nothing can be read anymore, but the client should still learn why it gets disconnected
+                    let _ = send_error(&mut peer.codec, &err).await;
+                    Err(err)
+                },
+                None => break,
+            },
+        };
+        if let Err(err) = result {
+            eprintln!("an error occurred while processing messages for {}; error = {}", user.usrname, err);
+            break;
+        };
+    }
+
+    // Disconnect user and notify other users
+    let mut state_lock : MutexGuard<Shared> = state.lock().await;
+    state_lock.send_presence(user.id, &Status { presence: Presence::Offline, text: None });
+    state_lock.peers.remove(&user.id);
+    state_lock.remove_transfers(user.id);
+    let msg = Message {
+        text: format!("{} has left the chat", user.usrname),
+        msg_type: MessageType::SystemInfo,
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    };
+    println!("{}", msg);
+    
+    // Clean up potentially now empty chats and tell the remaining members
+    let remaining_chats = state_lock.leave_all_chats(user.id);
+    for room in remaining_chats {
+        state_lock.send_to_chat(room, &msg, None);
+    };
+    std::mem::drop(state_lock);
+    Ok(())
+}
+
+
+/// Confirms the username to a user that just logged in and tells them what they can do
+/// and whether they were mentioned while they were gone
+async fn greet(peer: &mut Peer, user: &User, unread_mentions: usize) -> Result<(), ProtocolError> {
+    peer.codec.send_message(&Message {
+        text: user.usrname.clone(),
+        msg_type: MessageType::UserSetup(UserSetupType::UsernameConfirmed),
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    }).await?;
+    send_system_message(&mut peer.codec, String::from("Type /help to see all commands. Type /exit to quit.")).await?;
+    if unread_mentions > 0 {
+        send_system_message(&mut peer.codec, format!("You were mentioned {} time(s), type /mentions to see where", unread_mentions)).await?;
+    };
+    Ok(())
+}
+
+
+/// Handles a single message the client sent: either a command or something to say in a room
+async fn handle_message(state: Arc<Mutex<Shared>>, msg: Message, user: &mut User, peer: &mut Peer, registry: &CommandRegistry) -> Result<(), ProtocolError> {
+    match msg.msg_type {
+        MessageType::Command(command) => {
+            // Checked here, so no command can forget to
+            if let Err(err) = command_permission(&state, &command, user, registry).await {
+                let outcome = CommandOutcome::Failed(err.to_string());
+                return send_command_result(&mut peer.codec, command.name(), msg.request_id, outcome).await;
+            };
+            return handle_command(state, command, msg.request_id, user, peer, registry).await;
+        },
+        MessageType::Edit { .. } | MessageType::Delete { .. } => return change_message(state, msg, user, peer).await,
+        MessageType::Typing => {
+            send_typing(state, msg, user, peer).await;
+            return Ok(());
+        },
+        MessageType::Receipt(receipt) => {
+            update_receipt(state, msg.room, receipt, user, peer).await;
+            return Ok(());
+        },
+        MessageType::FileOffer { id: _, ref file } => {
+            let file = file.clone();
+            return transfers::offer_file(state, msg, file, user, peer).await;
+        },
+        MessageType::FileChunk { .. } => return transfers::add_file_chunk(state, msg, user, peer).await,
+        MessageType::User => (),
+        // Everything else is either sent by the server or only belongs to the handshake and login
+        _ => return send_error(&mut peer.codec, &ProtocolError::Unexpected).await,
+    };
+    // Only the sender gets its request id back, to match the message with the one it sent
+    let request_id = msg.request_id;
+    // Nobody gets to speak in the name of someone else, and only the server fills in what happened to the message
+    let msg = Message {
+        author: user.usrname.clone(),
+        msg_type: MessageType::User,
+        edited: None,
+        deleted: false,
+        reactions: Reactions::new(),
+        thread: None,
+        reply_count: 0,
+        recipient: None,
+        request_id: None,
+        ..msg
+    };
+    // The lock is released before telling the user why the message didn't go out
+    if let Err(reason) = post_message(&state, msg, request_id, user).await {
+        send_system_message(&mut peer.codec, reason).await?;
+    };
+    Ok(())
+}
+
+
+/// Sends the message of the user to the room it is meant for, with the request id for the sender only.
+/// Returns why not if that didn't work
+async fn post_message(state: &Arc<Mutex<Shared>>, msg: Message, request_id: Option<RequestId>, user: &User) -> Result<(), String> {
+    let mut state_lock = state.lock().await;
+    let room = state_lock.get_target_room(user.id, msg.room.as_deref()).map_err(|err| err.to_string())?;
+    state_lock.check_may_post(user.id, room).map_err(|err| err.to_string())?;
+    // A reply belongs to the thread of the message it answers
+    let thread = match msg.reply_to {
+        Some(parent) => Some(state_lock.thread_root(room, parent).map_err(|err| err.to_string())?),
+        None => None,
+    };
+    let mentions = state_lock.resolve_mentions(room, &msg.text);
+    let msg = Message { thread, mentions, ..msg };
+    println!("{}", msg);
+    let sender_msg = Message { request_id, ..msg.clone() };
+    state_lock.broadcast(user.id, room, &msg, &sender_msg).await.map_err(|err| err.to_string())?;
+    state_lock.add_unread_mentions(user.id, &msg.mentions);
+    if let Some(root) = thread {
+        if let Some(reply_count) = state_lock.add_reply(room, root) {
+            state_lock.send_to_chat(room, &Message {
+                msg_type: MessageType::ThreadUpdate { id: root, reply_count },
+                author: String::from(SYSTEM_USRNAME),
+                ..Message::new()
+            }, None);
+        };
+    };
+    Ok(())
+}
+
+
+/// Edits or deletes a message the user (or someone else, for moderators) sent before and
+/// tells everyone in the room about it
+async fn change_message(state: Arc<Mutex<Shared>>, msg: Message, user: &User, peer: &mut Peer) -> Result<(), ProtocolError> {
+    // The lock is released before telling the user why the message couldn't be changed
+    if let Err(reason) = edit_or_delete(&state, msg, user).await {
+        send_system_message(&mut peer.codec, reason).await?;
+    };
+    Ok(())
+}
+
+
+/// Applies the edit or deletion and passes it on to the room. Returns why not if that didn't work
+async fn edit_or_delete(state: &Arc<Mutex<Shared>>, msg: Message, user: &User) -> Result<(), String> {
+    let mut state_lock = state.lock().await;
+    let room = state_lock.get_target_room(user.id, msg.room.as_deref()).map_err(|err| err.to_string())?;
+    // The new text of an edit reaches the whole room just like a new message
+    if let MessageType::Edit { .. } = msg.msg_type {
+        state_lock.check_may_post(user.id, room).map_err(|err| err.to_string())?;
+        state_lock.check_not_muted(user.id, room).map_err(|err| err.to_string())?;
+    };
+    let changed = match msg.msg_type {
+        MessageType::Edit { id } => state_lock.edit_message(user.id, room, id, msg.text),
+        MessageType::Delete { id } => state_lock.delete_message(user.id, room, id),
+        _ => return Ok(()),
+    };
+    let changed = changed.map_err(|err| err.to_string())?;
+    let notice = Message {
+        text: changed.text,
+        msg_type: msg.msg_type,
+        author: user.usrname.clone(),
+        mentions: changed.mentions,
+        ..Message::new()
+    };
+    println!("{} changed message {:?}: {:?}", user.usrname, changed.id, notice.msg_type);
+    state_lock.send_to_chat(room, &notice, None);
+    Ok(())
+}
+
+
+/// Passes on that the user is typing in the room. Typing notices are frequent and
+/// don't matter much, so ones that can't be delivered are dropped without telling the client
+async fn send_typing(state: Arc<Mutex<Shared>>, msg: Message, user: &User, peer: &Peer) {
+    if !peer.supports(Capability::Typing) {
+        return;
+    };
+    let state_lock = state.lock().await;
+    if let Ok(room) = state_lock.get_target_room(user.id, msg.room.as_deref()) {
+        state_lock.send_typing(user, room);
+    };
+}
+
+
+/// Records how far the user got in the room and tells the other members about it.
+/// Like typing notices, receipts for rooms the user isn't in are dropped silently
+async fn update_receipt(state: Arc<Mutex<Shared>>, room: Option<String>, receipt: Receipt, user: &User, peer: &Peer) {
+    if !peer.supports(Capability::Receipts) {
+        return;
+    };
+    let mut state_lock = state.lock().await;
+    let room = match state_lock.get_target_room(user.id, room.as_deref()) {
+        Ok(room) => room,
+        Err(_) => return,
+    };
+    if state_lock.update_receipt(user.id, room, receipt) {
+        state_lock.send_receipts(room);
+    };
+}
+
+
+/// Waits for the Hello of the client and answers with the version and capabilities both sides support.
+/// Clients with another protocol version or that don't say Hello in time get an error and are disconnected
+async fn handshake(codec: &mut Codec, conn: ConnectionId) -> Result<BTreeSet<Capability>, Box<dyn Error>> {
+    let msg = match tokio::time::timeout(HANDSHAKE_TIMEOUT, codec.next_message()).await {
+        Ok(Some(Ok(msg))) => msg,
+        Ok(Some(Err(err))) => {
+            let _ = send_error(codec, &err).await;
+            return Err(err.into());
+        },
+        Ok(None) => return Err("connection closed during handshake".into()),
+        // Clients from before the handshake wait for the server to speak first
+        Err(_) => {
+            let _ = send_error(codec, &ProtocolError::MissingHello).await;
+            return Err(ProtocolError::MissingHello.into());
+        },
+    };
+    let (version, client, capabilities) = match msg.msg_type {
+        MessageType::Hello { version, client, capabilities } => (version, client, capabilities),
+        _ => {
+            send_error(codec, &ProtocolError::MissingHello).await?;
+            return Err(ProtocolError::MissingHello.into());
+        },
+    };
+    let version = match protocol::check_version(version) {
+        Ok(version) => version,
+        Err(err) => {
+            send_error(codec, &err).await?;
+            return Err(err.into());
+        },
+    };
+    let capabilities = protocol::negotiate_capabilities(&SERVER_CAPABILITIES, &capabilities);
+    println!("{} uses {} with protocol version {} and {:?}", conn, client, version, capabilities);
+    codec.send_message(&Message {
+        msg_type: MessageType::Welcome {
+            version,
+            server: format!("RustyChat server {}", env!("CARGO_PKG_VERSION")),
+            capabilities: capabilities.clone(),
+        },
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    }).await?;
+    Ok(capabilities)
+}
+
+
+/// Runs the login handshake until the client is logged in, has registered or joined as a guest.
+/// Returns the registered user together with the reciever of its messages
+async fn login(state: &Arc<Mutex<Shared>>, codec: &mut Codec, conn: ConnectionId, addr: SocketAddr, capabilities: &BTreeSet<Capability>)
+    -> Result<(User, Reciever), Box<dyn Error>>
+{
+    let guests_allowed = state.lock().await.config.allow_guests;
+    codec.send_message(&Message {
+        text: String::from("Please log in or register"),
+        msg_type: MessageType::UserSetup(UserSetupType::LoginRequired { guests_allowed }),
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    }).await?;
+
+    loop {
+        let msg = match codec.next_message().await {
+            Some(Ok(msg)) => msg,
+            Some(Err(err)) if err.is_recoverable() => {
+                send_error(codec, &err).await?;
+                continue;
+            },
+            Some(Err(err)) => {
+                let _ = send_error(codec, &err).await;
+                return Err(err.into());
+            },
+            None => return Err("connection closed during login".into()),
+        };
+        let attempt = match msg.msg_type {
+            MessageType::UserSetup(UserSetupType::Login { username, password }) => {
+                login_account(state, &username, &password).await
+            },
+            MessageType::UserSetup(UserSetupType::Register { username, password }) => {
+                let registered = register_account(state, &username, &password).await;
+                if let Ok((_, username)) = &registered {
+                    codec.send_message(&Message {
+                        text: username.clone(),
+                        msg_type: MessageType::UserSetup(UserSetupType::Registered),
+                        author: String::from(SYSTEM_USRNAME),
+                        ..Message::new()
+                    }).await?;
+                };
+                registered
+            },
+            MessageType::UserSetup(UserSetupType::Guest { username }) if guests_allowed => {
+                Ok((state.lock().await.next_user_id(), username.trim().to_string()))
+            },
+            MessageType::UserSetup(UserSetupType::Guest { .. }) => {
+                Err(String::from("Guests are not allowed on this server, please register"))
+            },
+            _ => Err(String::from("Please log in first")),
+        };
+
+        let reason = match attempt {
+            Ok((id, username)) => {
+                let user = User {
+                    id,
+                    conn,
+                    addr,
+                    usrname: username,
+                };
+                let mut state_lock = state.lock().await;
+                let registered = state_lock.add_peer(user.clone(), capabilities.clone());
+                std::mem::drop(state_lock);
+                match registered {
+                    Ok(reciever) => return Ok((user, reciever)),
+                    Err(err) => err.to_string(),
+                }
+            },
+            Err(reason) => reason,
+        };
+        codec.send_message(&Message {
+            text: reason,
+            msg_type: MessageType::UserSetup(UserSetupType::LoginFailed),
+            author: String::from(SYSTEM_USRNAME),
+            ..Message::new()
+        }).await?;
+    }
+}
+
+
+/// Checks the password of an existing account and returns its id and username
+async fn login_account(state: &Arc<Mutex<Shared>>, username: &str, password: &str) -> Result<(UserId, String), String> {
+    let account = state.lock().await.accounts.get(username.trim()).cloned()
+        .ok_or_else(|| AccountError::UnknownUser(username.to_string()));
+    // The lock is not held here as verifying takes a while
+    let verified = account.and_then(|account| accounts::verify_password(&account, password).map(|_| account));
+    match verified {
+        Ok(account) => Ok((account.id, account.username)),
+        Err(err) => {
+            // Only the log says what went wrong
+            println!("Failed login as {}: {}", username, err);
+            Err(AccountError::LoginFailed.to_string())
+        },
+    }
+}
+
+
+/// Creates a new account and returns its id and username
+async fn register_account(state: &Arc<Mutex<Shared>>, username: &str, password: &str) -> Result<(UserId, String), String> {
+    let username = username.trim();
+    let mut state_lock = state.lock().await;
+    let id = state_lock.next_user_id();
+    state_lock.check_username_available(id, username).map_err(|err| err.to_string())?;
+    std::mem::drop(state_lock);
+
+    // The lock is not held here as hashing takes a while
+    let password_hash = accounts::hash_password(password).map_err(|err| err.to_string())?;
+
+    let mut state_lock = state.lock().await;
+    // Someone might have taken the name in the meantime
+    state_lock.check_username_available(id, username).map_err(|err| err.to_string())?;
+    let account = state_lock.accounts.register(id, username, password_hash).map_err(|err| err.to_string())?;
+    println!("Registered account {} ({})", account.username, account.id);
+    Ok((account.id, account.username.clone()))
+}
+
+
+/// Sends a single system message to the current client only
+async fn send_system_message(codec: &mut Codec, text: String) -> Result<(), ProtocolError> {
+    codec.send_message(&system_message(text)).await
+}
+
+
+/// A message from the server to a single user, e.g. telling them how something went
+fn system_message(text: String) -> Message {
+    Message {
+        text,
+        msg_type: MessageType::SystemInfo,
+        author: String::from(SYSTEM_USRNAME),
+        timestamp: Some(Utc::now()),
+        ..Message::new()
+    }
+}
+
+
+/// Tells the client how its command went. Clients that didn't set a request id
+/// only hear about failures, as a system message
+async fn send_command_result(codec: &mut Codec, command: &str, request_id: Option<RequestId>, outcome: CommandOutcome) -> Result<(), ProtocolError> {
+    match (request_id, outcome) {
+        (None, CommandOutcome::Done(_)) => Ok(()),
+        (None, CommandOutcome::Failed(reason)) => send_system_message(codec, reason).await,
+        (Some(request_id), outcome) => codec.send_message(&Message {
+            text: match &outcome {
+                CommandOutcome::Failed(reason) => reason.clone(),
+                CommandOutcome::Done(_) => String::new(),
+            },
+            msg_type: MessageType::CommandResult { command: command.to_string(), outcome },
+            author: String::from(SYSTEM_USRNAME),
+            timestamp: Some(Utc::now()),
+            request_id: Some(request_id),
+            ..Message::new()
+        }).await,
+    }
+}
+
+
+/// Tells the client that something it sent could not be understood
+async fn send_error(codec: &mut Codec, err: &ProtocolError) -> Result<(), ProtocolError> {
+    codec.send_message(&Message {
+        text: err.to_string(),
+        msg_type: MessageType::Error,
+        author: String::from(SYSTEM_USRNAME),
+        timestamp: Some(Utc::now()),
+        ..Message::new()
+    }).await
+}
+
+
+/// Lets the client of the user know which room its messages go to now
+fn send_active_room(state_lock: &mut MutexGuard<'_, Shared>, user: UserId) {
+    let room = state_lock.get_active_room(user)
+        .and_then(|room| state_lock.get_chat(room))
+        .map(|chat| chat.name.clone());
+    let msg = Message {
+        msg_type: MessageType::ActiveRoom,
+        author: String::from(SYSTEM_USRNAME),
+        room,
+        ..Message::new()
+    };
+    state_lock.send_to_user(user, &msg);
+}
+
+
+/// What has been said in the room before the user joined, framed so they see where it starts and ends.
+/// Sent once the lock is released, see `send_all`
+fn history_replay(state_lock: &MutexGuard<'_, Shared>, room_name: &str, peer: &Peer) -> Vec<Message> {
+    if !peer.supports(Capability::History) {
+        return Vec::new();
+    };
+    let mut replay = state_lock.history.recent(room_name, state_lock.config.history_replay);
+    if replay.is_empty() {
+        return replay;
+    };
+    replay.insert(0, system_message(format!("--- Last {} message(s) in #{} ---", replay.len(), room_name)));
+    replay.push(system_message(String::from("--- End of history ---")));
+    replay
+}
+
+
+/// Tells the room that someone joined. Returns the replayed history and greeting for the joining user,
+/// to be sent once the lock is released
+fn announce_join(state_lock: &mut MutexGuard<'_, Shared>, room: RoomId, user: &User, peer: &Peer) -> Vec<Message> {
+    let room_name = match state_lock.get_chat(room) {
+        Some(chat) => chat.name.clone(),
+        None => return Vec::new(),
+    };
+    let msg = Message {
+        text: format!("{} has joined #{}", user.usrname, room_name),
+        msg_type: MessageType::SystemInfo,
+        author: String::from(SYSTEM_USRNAME),
+        ..Message::new()
+    };
+    println!("{}", msg);
+    state_lock.send_to_chat(room, &msg, Some(user.id));
+    let mut greeting = history_replay(state_lock, &room_name, peer);
+    // Shows the newcomer how far the others got in the messages that were just replayed
+    state_lock.send_receipts(room);
+    state_lock.send_room_presence(room, user.id);
+    greeting.push(system_message(format!("Welcome to #{} {}!", room_name, user.usrname)));
+    send_active_room(state_lock, user.id);
+    greeting
+}
+
+
+/// Writes the messages to the client one after the other. Never call it while holding the lock,
+/// a client that doesn't read would keep everyone else waiting
+async fn send_all(codec: &mut Codec, messages: Vec<Message>) -> Result<(), ProtocolError> {
+    for message in messages.iter() {
+        codec.send_message(message).await?;
+    };
+    Ok(())
+}
+
+
+/// Checks that the user has the permission the command needs, in the room they are talking in if it is about rooms
+async fn command_permission(state: &Arc<Mutex<Shared>>, command: &Command, user: &User, registry: &CommandRegistry) -> Result<(), PermissionError> {
+    // Commands about another room check the permission there themselves
+    let permission = match registry.find(command.name())
+        .filter(|chat_command| !chat_command.resolves_room())
+        .and_then(|chat_command| chat_command.permission()) {
+        Some(permission) => permission,
+        None => return Ok(()),
+    };
+    let state_lock = state.lock().await;
+    state_lock.check_permission(user.id, state_lock.get_active_room(user.id), permission)
+}
+
+
+/// Looks up the command in the registry and runs it. The user has to be allowed to use it (see `command_permission`).
+/// The outcome is sent back with the request id the client gave the command
+async fn handle_command(state: Arc<Mutex<Shared>>, command: Command, request_id: Option<RequestId>, user: &mut User, peer: &mut Peer, registry: &CommandRegistry) -> Result<(), ProtocolError> {
+    let name = command.name().to_string();
+    let outcome = match registry.find(&name) {
+        None => CommandOutcome::Failed(CommandError::Unknown(name.clone()).to_string()),
+        Some(chat_command) => {
+            let mut ctx = CommandContext { state, user, peer: &mut *peer, registry };
+            chat_command.run(&mut ctx, command).await?
+        },
+    };
+    send_command_result(&mut peer.codec, &name, request_id, outcome).await
+}
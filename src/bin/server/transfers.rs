@@ -0,0 +1,124 @@
+use chrono::Utc;
+use tokio::sync::Mutex;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusty_chat::{
+    Transmitter,
+    shared::Shared,
+    peer::{Peer, User},
+    message::Message,
+    types::{MessageType, RequestId, TransferId},
+    transfer::{self, FileInfo, TransferTarget, TransferError},
+    protocol::{ProtocolError, Capability},
+    permissions::Permission,
+};
+
+use super::SYSTEM_USRNAME;
+
+/// Pause between two chunks of a download, so the chat messages of the user get through in between
+const CHUNK_INTERVAL : Duration = Duration::from_millis(5);
+
+
+/// Registers the file the user wants to send to a room or another user and tells the client
+/// it can start uploading, or why it can't
+pub async fn offer_file(state: Arc<Mutex<Shared>>, msg: Message, file: FileInfo, user: &User, peer: &mut Peer) -> Result<(), ProtocolError> {
+    if !peer.supports(Capability::FileTransfer) {
+        return send_failure(peer, None, msg.request_id, "Your client doesn't support sending files".to_string()).await;
+    };
+    let mut state_lock = state.lock().await;
+    let target = match &msg.recipient {
+        Some(recipient) => state_lock.get_usr_from_name(recipient.clone())
+            .map(|recipient| (TransferTarget::User(recipient.id), None))
+            .ok_or_else(|| TransferError::CantReceive(recipient.clone()).to_string()),
+        None => state_lock.get_target_room(user.id, msg.room.as_deref())
+            .map(|room| (TransferTarget::Room(state_lock.get_chat(room).map(|chat| chat.name.clone()).unwrap_or_default()), Some(room)))
+            .map_err(|err| err.to_string()),
+    };
+    let offered = target.and_then(|(target, room)| {
+        // Files only go where messages may go
+        if let Some(room) = room {
+            state_lock.check_may_post(user.id, room).map_err(|err| err.to_string())?;
+            state_lock.check_not_muted(user.id, room).map_err(|err| err.to_string())?;
+        };
+        state_lock.check_permission(user.id, room, Permission::SendFiles).map_err(|err| err.to_string())?;
+        state_lock.offer_file(user.id, target, file.clone()).map_err(|err| err.to_string())
+    });
+    std::mem::drop(state_lock);
+    match offered {
+        Ok(id) => {
+            println!("{} is uploading {} ({}) as transfer {}", user.usrname, file.name, transfer::format_size(file.size), id);
+            peer.codec.send_message(&Message {
+                msg_type: MessageType::UploadReady { id },
+                author: String::from(SYSTEM_USRNAME),
+                request_id: msg.request_id,
+                ..Message::new()
+            }).await
+        },
+        Err(reason) => send_failure(peer, None, msg.request_id, reason).await,
+    }
+}
+
+
+/// Adds a chunk to the file the user is uploading. Once it is complete,
+/// the user hears about it and everyone it was sent to gets offered the file
+pub async fn add_file_chunk(state: Arc<Mutex<Shared>>, msg: Message, user: &User, peer: &mut Peer) -> Result<(), ProtocolError> {
+    let MessageType::FileChunk { id, seq, data, checksum } = msg.msg_type else {
+        return Ok(());
+    };
+    let mut state_lock = state.lock().await;
+    match state_lock.add_file_chunk(user.id, id, seq, &data, &checksum) {
+        Ok(None) => Ok(()),
+        Ok(Some(transfer)) => {
+            println!("{} finished uploading transfer {}", user.usrname, id);
+            state_lock.send_file_offer(&transfer);
+            std::mem::drop(state_lock);
+            peer.codec.send_message(&Message {
+                text: format!("{} can be downloaded with /accept {} now", transfer.file.name, id),
+                msg_type: MessageType::TransferDone { id },
+                author: String::from(SYSTEM_USRNAME),
+                timestamp: Some(Utc::now()),
+                ..Message::new()
+            }).await
+        },
+        Err(err) => {
+            std::mem::drop(state_lock);
+            eprintln!("Transfer {} of {} failed: {}", id, user.usrname, err);
+            send_failure(peer, Some(id), None, err.to_string()).await
+        },
+    }
+}
+
+
+/// Sends the chunks of a file that is being downloaded through the transmitter of the user.
+/// Meant to run in its own task, so the connection keeps handling everything else meanwhile
+pub async fn stream_file(transmitter: Transmitter, id: TransferId, data: Arc<Vec<u8>>) {
+    let mut seq = 0;
+    while let Some(chunk) = transfer::chunk(id, &data, seq) {
+        let send : String = Message {
+            msg_type: chunk,
+            author: String::from(SYSTEM_USRNAME),
+            ..Message::new()
+        }.into();
+        // The user disconnected
+        if transmitter.send(send).is_err() {
+            return;
+        };
+        seq += 1;
+        tokio::time::sleep(CHUNK_INTERVAL).await;
+    };
+}
+
+
+/// Tells the client that a transfer (or, without an id, the offer with the request id) didn't work out
+async fn send_failure(peer: &mut Peer, id: Option<TransferId>, request_id: Option<RequestId>, reason: String) -> Result<(), ProtocolError> {
+    peer.codec.send_message(&Message {
+        text: reason,
+        msg_type: MessageType::TransferFailed { id },
+        author: String::from(SYSTEM_USRNAME),
+        timestamp: Some(Utc::now()),
+        request_id,
+        ..Message::new()
+    }).await
+}
@@ -1,9 +1,14 @@
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 use tokio::{net::TcpStream};
 use futures::SinkExt;
 use tokio_stream::StreamExt;
 
 use crate::message::Message;
+use crate::protocol::ProtocolError;
+
+
+/// Longest line (in bytes) that is accepted from a client. A longer line ends the connection
+pub const MAX_LINE_LEN : usize = 64 * 1024;
 
 
 /// A wrapper around the provided Frame by tokio. It helps providing a single way
@@ -15,16 +20,41 @@ pub struct Codec {
 impl Codec {
     pub fn new(stream: TcpStream) -> Codec {
         Codec {
-            lines: Framed::new(stream, LinesCodec::new()),
+            lines: Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LEN)),
         }
     }
 
-    pub async fn send_message(&mut self, message: &Message) {
-        let serialized: String = message.into();
-        self.lines.send(serialized).await.unwrap();
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        self.send_line(message.into()).await
+    }
+
+    /// Sends a message that was already serialized, e.g. by `Shared` for another connection
+    pub async fn send_line(&mut self, line: String) -> Result<(), ProtocolError> {
+        self.lines.send(line).await?;
+        Ok(())
     }
 
-    pub async fn next(&mut self) -> Option<Result<String, tokio_util::codec::LinesCodecError>> {
-        self.lines.next().await
+    /// Reads the next message, skipping empty lines. Returns None once the connection is closed.
+    /// A line that can't be parsed is returned as an error, the connection can still be used after it
+    pub async fn next_message(&mut self) -> Option<Result<Message, ProtocolError>> {
+        loop {
+            let line = match self.lines.next().await? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if !line.trim().is_empty() {
+                return Some(Message::try_from(line.as_str()));
+            };
+        }
     }
-}
\ No newline at end of file
+}
+
+
+impl From<LinesCodecError> for ProtocolError {
+    fn from(err: LinesCodecError) -> Self {
+        match err {
+            LinesCodecError::MaxLineLengthExceeded => ProtocolError::LineTooLong,
+            LinesCodecError::Io(err) => ProtocolError::Io(err),
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use std::time::Duration;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::search::SearchQuery;
+use crate::permissions::Role;
+use crate::types::{MessageId, Presence, TransferId};
+
+
+/// Every command the client knows and how to use it.
+/// The server may know even more, those are sent as `Command::Other`
+pub const COMMAND_USAGES : [(&str, &str); 25] = [
+    ("create", "/create #<room> [topic]"),
+    ("join", "/join #<room> or /join <username>"),
+    ("leave", "/leave #<room>"),
+    ("switch", "/switch #<room>"),
+    ("msg", "/msg <username> <text>"),
+    ("nick", "/nick <new username>"),
+    ("register", "/register <password>"),
+    ("history", "/history [count] [before <message-id> | around <message-id>] [in #room]"),
+    ("search", "/search <query> [in #room] [from user]"),
+    ("rooms", "/rooms"),
+    ("react", "/react <message-id> <emoji or :shortcode:>"),
+    ("thread", "/thread <message-id>"),
+    ("status", "/status [online | away | busy] [text]"),
+    ("mentions", "/mentions"),
+    ("send", "/send <path> [to <username> | to #<room>]"),
+    ("accept", "/accept <transfer-id> [destination]"),
+    ("kick", "/kick <username>"),
+    ("ban", "/ban <username> [address]"),
+    ("unban", "/unban <username>"),
+    ("mute", "/mute <username> [duration, e.g. 10m]"),
+    ("unmute", "/unmute <username>"),
+    ("op", "/op <username>"),
+    ("role", "/role <username> <guest | member | moderator | admin | default> [in #room]"),
+    ("readonly", "/readonly"),
+    ("help", "/help [command]"),
+];
+
+/// Shorter names for some of the commands
+pub const COMMAND_ALIASES : [(&str, &str); 4] = [
+    ("j", "join"),
+    ("part", "leave"),
+    ("dm", "msg"),
+    ("?", "help"),
+];
+
+/// Commands the client handles itself, with their usage and what they do.
+/// The server doesn't know them, but lists them in /help next to its own
+pub const CLIENT_COMMANDS : [(&str, &str, &str); 4] = [
+    ("edit", "/edit [message-id] <new text>", "Changes the text of your last message in the current room, or of the given one"),
+    ("delete", "/delete [message-id]", "Deletes your last message in the current room, or the given one"),
+    ("reply", "/reply <message-id> <text>", "Replies to a message in the room it was sent to"),
+    ("r", "/r <text>", "Replies to the last private message you got"),
+];
+
+
+/// Where in the history of a room /history should start
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum HistoryPosition {
+    /// The messages sent before this one
+    Before(MessageId),
+    /// The messages sent around this one, with it in the middle
+    Around(MessageId),
+}
+
+
+/// A command typed by the user, already split into its arguments by `Command::parse`.
+/// It gets sent to the server inside `MessageType::Command`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum Command {
+    Create { room: String, topic: String },
+    Join { room: String },
+    /// Join the room another user is in
+    JoinUser { username: String },
+    Leave { room: String },
+    Switch { room: String },
+    Msg { recipient: String, text: String },
+    Nick { username: String },
+    Register { password: String },
+    History { count: Option<usize>, position: Option<HistoryPosition>, room: Option<String> },
+    Search(SearchQuery),
+    Rooms,
+    /// Adds the reaction to the message, or takes it back if it was there already
+    React { id: MessageId, reaction: String },
+    /// Show the thread the message belongs to
+    Thread { id: MessageId },
+    /// Changes the presence and/or the status text, the ones that are None stay as they are.
+    /// An empty text removes it
+    Status { presence: Option<Presence>, text: Option<String> },
+    /// Lists the newest messages that mention the user
+    Mentions,
+    /// Offers a file to a user or a room, by default the active one.
+    /// Clients read the file themselves and send a `MessageType::FileOffer` instead
+    Send { path: String, to: Option<String> },
+    /// Downloads the offered file. Where it is saved is up to the client, which doesn't need to tell the server
+    Accept { id: TransferId, dest: Option<String> },
+    /// Removes a member from the active room
+    Kick { username: String },
+    /// Keeps the user out of the active room, with `address` everyone connecting from their address as well
+    Ban { username: String, address: bool },
+    Unban { username: String },
+    /// Stops a member from sending messages to the active room, for the duration or until unmuted
+    Mute { username: String, duration: Option<Duration> },
+    Unmute { username: String },
+    /// Makes a member an operator of the active room, or takes it back if they are one already
+    Op { username: String },
+    /// Gives the user a role on the server, or only in the room. None goes back to the default
+    Role { username: String, role: Option<Role>, room: Option<String> },
+    /// Makes the active room read-only, or writable again
+    ReadOnly,
+    Help { command: Option<String> },
+    /// A command the client doesn't know itself, the server decides what to do with it
+    Other { name: String, args: Vec<String> },
+}
+
+
+/// How a command went, sent back in `MessageType::CommandResult`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum CommandOutcome {
+    /// The command was carried out, with what came out of it
+    Done(CommandPayload),
+    /// The command could not be carried out, holds why
+    Failed(String),
+}
+
+
+/// What a successful command produced
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum CommandPayload {
+    Empty,
+    /// The room the command was about (without the leading '#')
+    Room(String),
+    /// Names of rooms (without the leading '#')
+    Rooms(Vec<String>),
+    /// The user the command was about
+    Username(String),
+    /// How many messages were found
+    Count(usize),
+}
+
+
+/// Reasons why a line can't be turned into a `Command`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CommandError {
+    /// The line doesn't start with '/'
+    NotACommand,
+    /// Nothing follows the '/'
+    MissingName,
+    Unknown(String),
+    /// The arguments don't fit the command, holds how it is used
+    Usage(&'static str),
+    UnterminatedQuote,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotACommand => write!(f, "Commands start with '/'"),
+            CommandError::MissingName => write!(f, "Type /help to see all commands"),
+            CommandError::Unknown(name) => write!(f, "Unknown command /{}, type /help to see all commands", name),
+            CommandError::Usage(usage) => write!(f, "Usage: {}", usage),
+            CommandError::UnterminatedQuote => write!(f, "A quote is missing its closing quote"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+
+impl Command {
+    /// Parses a line like `/create #dev "Talk about development"`.
+    /// Arguments are separated by spaces, an argument starting with a quote (" or ') goes
+    /// until the closing quote and a backslash takes the next character as it is
+    pub fn parse(input: &str) -> Result<Command, CommandError> {
+        let input = input.trim().strip_prefix('/').ok_or(CommandError::NotACommand)?;
+        let mut args = Args { rest: input };
+        let name = args.next()?.ok_or(CommandError::MissingName)?.to_lowercase();
+        let name = Command::resolve_alias(&name).to_string();
+        let usage = match Command::usage(&name) {
+            Some(usage) => usage,
+            // Not ours to check, the server might know it
+            None => {
+                let mut rest = Vec::new();
+                while let Some(arg) = args.next()? {
+                    rest.push(arg);
+                };
+                return Ok(Command::Other { name, args: rest });
+            },
+        };
+        let wrong_usage = || CommandError::Usage(usage);
+
+        let command = match name.as_str() {
+            "create" => Command::Create {
+                room: args.next()?.ok_or_else(wrong_usage)?,
+                topic: args.rest(),
+            },
+            "join" => match args.next()?.ok_or_else(wrong_usage)? {
+                room if room.starts_with('#') => Command::Join { room },
+                username => Command::JoinUser { username },
+            },
+            "leave" => Command::Leave { room: args.next()?.ok_or_else(wrong_usage)? },
+            "switch" => Command::Switch { room: args.next()?.ok_or_else(wrong_usage)? },
+            "msg" => {
+                let recipient = args.next()?.ok_or_else(wrong_usage)?;
+                let text = args.rest();
+                if text.is_empty() {
+                    return Err(wrong_usage());
+                };
+                Command::Msg { recipient, text }
+            },
+            "nick" => Command::Nick { username: args.next()?.ok_or_else(wrong_usage)? },
+            "register" => Command::Register { password: args.next()?.ok_or_else(wrong_usage)? },
+            "history" => {
+                let (mut count, mut position, mut room) = (None, None, None);
+                while let Some(arg) = args.next()? {
+                    match arg.as_str() {
+                        "before" | "around" => {
+                            let id = args.next()?.and_then(|id| id.parse().ok()).ok_or_else(wrong_usage)?;
+                            position = Some(if arg == "before" { HistoryPosition::Before(id) } else { HistoryPosition::Around(id) });
+                        },
+                        "in" => room = Some(args.next()?.ok_or_else(wrong_usage)?),
+                        _ => count = Some(arg.parse().map_err(|_| wrong_usage())?),
+                    };
+                };
+                Command::History { count, position, room }
+            },
+            "search" => {
+                let mut words = Vec::new();
+                while let Some(word) = args.next()? {
+                    words.push(word);
+                };
+                let words : Vec<&str> = words.iter().map(String::as_str).collect();
+                Command::Search(SearchQuery::parse(&words).map_err(|_| wrong_usage())?)
+            },
+            "rooms" => Command::Rooms,
+            "react" => Command::React {
+                id: args.next()?.and_then(|id| id.parse().ok()).ok_or_else(wrong_usage)?,
+                reaction: args.next()?.ok_or_else(wrong_usage)?,
+            },
+            "thread" => Command::Thread {
+                id: args.next()?.and_then(|id| id.parse().ok()).ok_or_else(wrong_usage)?,
+            },
+            "status" => {
+                let all = args.rest;
+                match args.next()?.as_deref().and_then(Presence::parse) {
+                    Some(presence) => {
+                        let text = args.rest();
+                        Command::Status { presence: Some(presence), text: (!text.is_empty()).then_some(text) }
+                    },
+                    // Just a text, or nothing at all to go back to being online without one
+                    None => {
+                        args.rest = all;
+                        match args.rest() {
+                            text if text.is_empty() => Command::Status { presence: Some(Presence::Online), text: Some(text) },
+                            text => Command::Status { presence: None, text: Some(text) },
+                        }
+                    },
+                }
+            },
+            "mentions" => Command::Mentions,
+            "send" => {
+                let path = args.next()?.ok_or_else(wrong_usage)?;
+                let to = match args.next()?.as_deref() {
+                    Some("to") => Some(args.next()?.ok_or_else(wrong_usage)?),
+                    Some(_) => return Err(wrong_usage()),
+                    None => None,
+                };
+                Command::Send { path, to }
+            },
+            "accept" => Command::Accept {
+                id: args.next()?.and_then(|id| id.parse().ok()).ok_or_else(wrong_usage)?,
+                dest: args.next()?,
+            },
+            "kick" => Command::Kick { username: args.next()?.ok_or_else(wrong_usage)? },
+            "ban" => Command::Ban {
+                username: args.next()?.ok_or_else(wrong_usage)?,
+                address: match args.next()?.as_deref() {
+                    Some("address") => true,
+                    Some(_) => return Err(wrong_usage()),
+                    None => false,
+                },
+            },
+            "unban" => Command::Unban { username: args.next()?.ok_or_else(wrong_usage)? },
+            "mute" => Command::Mute {
+                username: args.next()?.ok_or_else(wrong_usage)?,
+                duration: match args.next()? {
+                    Some(duration) => Some(parse_duration(&duration).ok_or_else(wrong_usage)?),
+                    None => None,
+                },
+            },
+            "unmute" => Command::Unmute { username: args.next()?.ok_or_else(wrong_usage)? },
+            "op" => Command::Op { username: args.next()?.ok_or_else(wrong_usage)? },
+            "role" => Command::Role {
+                username: args.next()?.ok_or_else(wrong_usage)?,
+                role: match args.next()?.ok_or_else(wrong_usage)?.as_str() {
+                    "default" => None,
+                    role => Some(Role::parse(role).ok_or_else(wrong_usage)?),
+                },
+                room: match args.next()?.as_deref() {
+                    Some("in") => Some(args.next()?.ok_or_else(wrong_usage)?),
+                    Some(_) => return Err(wrong_usage()),
+                    None => None,
+                },
+            },
+            "readonly" => Command::ReadOnly,
+            "help" => Command::Help {
+                command: args.next()?.map(|name| Command::resolve_alias(name.trim_start_matches('/')).to_lowercase()),
+            },
+            _ => return Err(CommandError::Unknown(name)),
+        };
+        // Anything left over means the user meant something else
+        if args.next()?.is_some() {
+            return Err(wrong_usage());
+        };
+        Ok(command)
+    }
+
+
+    /// How the command with this name (without the '/') is used
+    pub fn usage(name: &str) -> Option<&'static str> {
+        COMMAND_USAGES.iter().find(|(command, _)| *command == name).map(|(_, usage)| *usage)
+    }
+
+
+    /// The other names the command with this name can be typed as
+    pub fn aliases(name: &str) -> Vec<&'static str> {
+        COMMAND_ALIASES.iter().filter(|(_, command)| *command == name).map(|(alias, _)| *alias).collect()
+    }
+
+
+    /// The name of the command the alias stands for, or the name itself if it isn't an alias
+    pub fn resolve_alias(name: &str) -> &str {
+        COMMAND_ALIASES.iter().find(|(alias, _)| *alias == name).map_or(name, |(_, command)| *command)
+    }
+
+
+    /// Name of the command, as it is typed after the '/'
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Create { .. } => "create",
+            Command::Join { .. } | Command::JoinUser { .. } => "join",
+            Command::Leave { .. } => "leave",
+            Command::Switch { .. } => "switch",
+            Command::Msg { .. } => "msg",
+            Command::Nick { .. } => "nick",
+            Command::Register { .. } => "register",
+            Command::History { .. } => "history",
+            Command::Search(_) => "search",
+            Command::Rooms => "rooms",
+            Command::React { .. } => "react",
+            Command::Thread { .. } => "thread",
+            Command::Status { .. } => "status",
+            Command::Mentions => "mentions",
+            Command::Send { .. } => "send",
+            Command::Accept { .. } => "accept",
+            Command::Kick { .. } => "kick",
+            Command::Ban { .. } => "ban",
+            Command::Unban { .. } => "unban",
+            Command::Mute { .. } => "mute",
+            Command::Unmute { .. } => "unmute",
+            Command::Op { .. } => "op",
+            Command::Role { .. } => "role",
+            Command::ReadOnly => "readonly",
+            Command::Help { .. } => "help",
+            Command::Other { name, .. } => name,
+        }
+    }
+}
+
+
+/// Reads durations like `30s`, `10m`, `2h` or `1d`. A number without a unit means minutes
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (amount, unit) = text.split_at(split);
+    let amount : u64 = amount.parse().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" | "" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(seconds)?)).filter(|duration| !duration.is_zero())
+}
+
+
+/// The duration the way `parse_duration` reads it, in the biggest unit that fits exactly
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        _ if seconds.is_multiple_of(24 * 60 * 60) => format!("{}d", seconds / (24 * 60 * 60)),
+        _ if seconds.is_multiple_of(60 * 60) => format!("{}h", seconds / (60 * 60)),
+        _ if seconds.is_multiple_of(60) => format!("{}m", seconds / 60),
+        _ => format!("{}s", seconds),
+    }
+}
+
+
+/// Splits the arguments of a command one at a time
+struct Args<'a> {
+    rest: &'a str,
+}
+
+impl Args<'_> {
+    /// The next argument with its quotes and escapes removed, or None if there are no more
+    fn next(&mut self) -> Result<Option<String>, CommandError> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return Ok(None);
+        };
+        let mut arg = String::new();
+        let mut quote = None;
+        let mut end = rest.len();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match (quote, c) {
+                (None, c) if c.is_whitespace() => {
+                    end = i;
+                    break;
+                },
+                (None, '"' | '\'') if i == 0 => quote = Some(c),
+                (Some(open), c) if c == open => quote = None,
+                (_, '\\') => arg.push(chars.next().map_or('\\', |(_, escaped)| escaped)),
+                (_, c) => arg.push(c),
+            };
+        };
+        if quote.is_some() {
+            return Err(CommandError::UnterminatedQuote);
+        };
+        self.rest = &rest[end..];
+        Ok(Some(arg))
+    }
+
+
+    /// Everything that is left as free text (e.g. the text of a /msg), keeping the spaces as typed.
+    /// If it is a single quoted argument, the quotes are removed
+    fn rest(&mut self) -> String {
+        let rest = self.rest.trim();
+        self.rest = "";
+        let mut quoted = Args { rest };
+        if let (true, Ok(Some(arg)), Ok(None)) = (rest.starts_with(['"', '\'']), quoted.next(), quoted.next()) {
+            return arg;
+        };
+        rest.to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_arguments_keep_their_spaces() {
+        assert_eq!(Command::parse("/create #dev \"Talk about development\""), Ok(Command::Create {
+            room: String::from("#dev"),
+            topic: String::from("Talk about development"),
+        }));
+        assert_eq!(Command::parse("/msg 'bob' it's   spaced"), Ok(Command::Msg {
+            recipient: String::from("bob"),
+            text: String::from("it's   spaced"),
+        }));
+    }
+
+    #[test]
+    fn backslashes_escape_the_next_character() {
+        assert_eq!(Command::parse(r#"/nick a\ b"#), Ok(Command::Nick { username: String::from("a b") }));
+        assert_eq!(Command::parse(r#"/create #dev "say \"hi\"""#), Ok(Command::Create {
+            room: String::from("#dev"),
+            topic: String::from("say \"hi\""),
+        }));
+        assert_eq!(Command::parse(r"/nick end\"), Ok(Command::Nick { username: String::from("end\\") }));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert_eq!(Command::parse("/create \"#dev topic"), Err(CommandError::UnterminatedQuote));
+        assert_eq!(Command::parse("/unknown 'open"), Err(CommandError::UnterminatedQuote));
+    }
+
+    #[test]
+    fn join_needs_an_argument() {
+        assert_eq!(Command::parse("/join"), Err(CommandError::Usage("/join #<room> or /join <username>")));
+        assert_eq!(Command::parse("/j #dev"), Ok(Command::Join { room: String::from("#dev") }));
+        assert_eq!(Command::parse("/join bob"), Ok(Command::JoinUser { username: String::from("bob") }));
+    }
+
+    #[test]
+    fn durations_have_units() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(10 * 60)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(10 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(format_duration(Duration::from_secs(2 * 60 * 60)), "2h");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+    }
+
+    #[test]
+    fn invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+        assert!(matches!(Command::parse("/mute bob soon"), Err(CommandError::Usage(_))));
+    }
+
+    #[test]
+    fn lines_without_a_command_name() {
+        assert_eq!(Command::parse("hello"), Err(CommandError::NotACommand));
+        assert_eq!(Command::parse("/  "), Err(CommandError::MissingName));
+        assert_eq!(Command::parse("/shrug a b"), Ok(Command::Other {
+            name: String::from("shrug"),
+            args: vec![String::from("a"), String::from("b")],
+        }));
+    }
+}
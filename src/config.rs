@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::DEFAULT_ADDR;
+use crate::permissions::{Permission, Role};
+
+
+/// Default location of the server configuration file
+pub const DEFAULT_CONFIG_PATH : &str = "server_config.json";
+
+
+/// Settings of the server, read from a JSON file.
+/// Every field is optional in the file and falls back to its default
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the server listens on
+    pub addr: String,
+    /// Whether users may chat without an account
+    pub allow_guests: bool,
+    /// File the registered accounts are stored in
+    pub accounts_path: PathBuf,
+    /// File the messages of every room get appended to.
+    /// Without one, only the newest `history_capacity` messages per room are kept in memory
+    pub history_path: Option<PathBuf>,
+    pub history_capacity: usize,
+    /// How many of the newest messages a user gets to see when joining a room
+    pub history_replay: usize,
+    /// Receipts are only passed on in rooms with at most this many members,
+    /// as every member acknowledges every message
+    pub receipts_room_limit: usize,
+    /// Largest file that may be sent, in bytes
+    pub max_file_size: u64,
+    /// How many bytes of files a user may have on the server at once.
+    /// Files stay until their sender disconnects
+    pub file_quota: u64,
+    /// Usernames of the accounts that are always admins, whatever role their account has
+    pub admins: Vec<String>,
+    /// The lowest role that has the permission, for the permissions that shouldn't have their default
+    pub permissions: BTreeMap<Permission, Role>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: DEFAULT_ADDR.to_string(),
+            allow_guests: true,
+            accounts_path: PathBuf::from("accounts.json"),
+            history_path: Some(PathBuf::from("history.jsonl")),
+            history_capacity: 1000,
+            history_replay: 20,
+            receipts_room_limit: 10,
+            max_file_size: 10 * 1024 * 1024,
+            file_quota: 50 * 1024 * 1024,
+            admins: Vec::new(),
+            permissions: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file. A missing file just means the defaults get used
+    pub fn load(path: &Path) -> io::Result<ServerConfig> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(ServerConfig::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+
+    /// The lowest role that has the permission on this server
+    pub fn required_role(&self, permission: Permission) -> Role {
+        self.permissions.get(&permission).copied().unwrap_or_else(|| permission.default_role())
+    }
+}
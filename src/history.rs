@@ -0,0 +1,352 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::message::Message;
+use crate::search::SearchQuery;
+use crate::types::{MessageId, UserId};
+
+
+/// Keeps the messages that were sent to each room, keyed by the room name.
+/// The server only talks to the trait, so the backend can be swapped out
+pub trait HistoryStore: Send + Sync {
+    /// Records a message that was broadcast to the room
+    fn append(&mut self, room: &str, message: &Message) -> io::Result<()>;
+
+    /// All messages of the room that are still stored, oldest first
+    fn messages<'a>(&'a self, room: &str) -> Box<dyn DoubleEndedIterator<Item = &'a Message> + 'a>;
+
+    /// Names of all rooms that have messages stored
+    fn rooms<'a>(&'a self) -> Box<dyn Iterator<Item = &'a str> + 'a>;
+
+    /// The highest message id in the store, so that ids keep increasing after a restart
+    fn last_id(&self) -> Option<MessageId>;
+
+    /// Replaces the stored message that has the same id (e.g. after it was edited).
+    /// Returns false if the room doesn't have that message (anymore)
+    fn replace(&mut self, room: &str, message: &Message) -> io::Result<bool>;
+
+    /// The highest user id that wrote or reacted to a stored message. Guests get new ids after a restart,
+    /// which must not be ones that can edit or delete their messages
+    fn max_user_id(&self) -> Option<UserId> {
+        self.rooms()
+            .flat_map(|room| self.messages(room))
+            .flat_map(|message| message.author_id.into_iter().chain(message.reactions.values().flatten().copied()))
+            .max()
+    }
+
+    /// The message of the room with this id, if it is still stored
+    fn get<'a>(&'a self, room: &str, id: MessageId) -> Option<&'a Message> {
+        self.messages(room).find(|message| message.id == Some(id))
+    }
+
+    /// Sequence number of the newest message of the room, so that a room that gets
+    /// created again continues where it left off
+    fn last_seq(&self, room: &str) -> Option<u64> {
+        self.messages(room).next_back()?.seq
+    }
+
+    /// Returns up to `count` messages of the room that were sent before the message with the id `before`
+    /// (or the newest ones if there is no id), oldest first
+    fn before(&self, room: &str, before: Option<MessageId>, count: usize) -> Vec<Message> {
+        let mut batch : Vec<Message> = self.messages(room)
+            .rev()
+            // Messages from before ids existed don't have one and are the oldest
+            .skip_while(|message| match (before, message.id) {
+                (Some(before), Some(id)) => id >= before,
+                _ => false,
+            })
+            .take(count)
+            .cloned()
+            .collect();
+        batch.reverse();
+        batch
+    }
+
+    /// Like `before`, but also tells whether there are even older messages left after the batch
+    fn page_before(&self, room: &str, before: Option<MessageId>, count: usize) -> (Vec<Message>, bool) {
+        // Ask for one more than needed to know if there is anything left after this batch
+        let mut messages = self.before(room, before, count + 1);
+        let has_more = messages.len() > count;
+        if has_more {
+            messages.remove(0);
+        };
+        (messages, has_more)
+    }
+
+    /// Returns up to `count` of the newest messages of the room, oldest first
+    fn recent(&self, room: &str, count: usize) -> Vec<Message> {
+        self.before(room, None, count)
+    }
+
+    /// Returns up to `count` messages of the room with the message with the id `around` in the middle, oldest first
+    fn around(&self, room: &str, around: MessageId, count: usize) -> Vec<Message> {
+        let mut batch = self.before(room, Some(around), count / 2);
+        let rest = count - batch.len();
+        batch.extend(self.messages(room)
+            .skip_while(|message| message.id.is_none_or(|id| id < around))
+            .take(rest)
+            .cloned());
+        batch
+    }
+
+    /// The message with the id `root` followed by every reply in its thread, oldest first
+    fn thread(&self, room: &str, root: MessageId) -> Vec<Message> {
+        self.messages(room)
+            .filter(|message| message.id == Some(root) || message.thread == Some(root))
+            .cloned()
+            .collect()
+    }
+
+    /// Up to `limit` of the newest messages in the given rooms that mention the user, newest first
+    fn mentions(&self, rooms: &[String], username: &str, limit: usize) -> Vec<Message> {
+        let mut mentions : Vec<&Message> = rooms.iter()
+            .flat_map(|room| self.messages(room))
+            .filter(|message| !message.deleted && message.mentions.iter().any(|name| name.eq_ignore_ascii_case(username)))
+            .collect();
+        mentions.sort_by_key(|message| std::cmp::Reverse(message.id));
+        mentions.into_iter().take(limit).cloned().collect()
+    }
+
+
+    /// Finds up to `limit` messages in the given rooms that match the query, best match first.
+    /// Equally good matches are sorted newest first
+    fn search(&self, rooms: &[String], query: &SearchQuery, limit: usize) -> Vec<Message> {
+        let mut hits : Vec<(usize, &Message)> = rooms.iter()
+            .flat_map(|room| self.messages(room))
+            .filter_map(|message| Some((query.score(message)?, message)))
+            .collect();
+        hits.sort_by(|(score, message), (other_score, other)| {
+            other_score.cmp(score).then(other.id.cmp(&message.id))
+        });
+        hits.into_iter().take(limit).map(|(_, message)| message.clone()).collect()
+    }
+}
+
+
+/// Keeps the newest messages of every room in memory and forgets the rest
+#[derive(Debug, Default)]
+pub struct MemoryHistory {
+    capacity: usize,
+    rooms: HashMap<String, VecDeque<Message>>,
+}
+
+impl MemoryHistory {
+    /// The capacity is the amount of messages kept per room
+    pub fn new(capacity: usize) -> MemoryHistory {
+        MemoryHistory {
+            capacity,
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn append(&mut self, room: &str, message: &Message) -> io::Result<()> {
+        let messages = self.rooms.entry(room.to_string()).or_default();
+        messages.push_back(message.clone());
+        while messages.len() > self.capacity {
+            messages.pop_front();
+        };
+        Ok(())
+    }
+
+    fn messages<'a>(&'a self, room: &str) -> Box<dyn DoubleEndedIterator<Item = &'a Message> + 'a> {
+        match self.rooms.get(room) {
+            Some(messages) => Box::new(messages.iter()),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn rooms<'a>(&'a self) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        Box::new(self.rooms.keys().map(String::as_str))
+    }
+
+    fn last_id(&self) -> Option<MessageId> {
+        self.rooms.values().filter_map(|messages| messages.back()?.id).max()
+    }
+
+    fn replace(&mut self, room: &str, message: &Message) -> io::Result<bool> {
+        let stored = self.rooms.get_mut(room)
+            .and_then(|messages| messages.iter_mut().find(|stored| stored.id.is_some() && stored.id == message.id));
+        match stored {
+            Some(stored) => {
+                *stored = message.clone();
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+
+/// A single line of the history log
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    room: String,
+    message: Message,
+}
+
+
+/// Appends every message as a JSON line to a file, so the history survives restarts.
+/// A changed message is appended again and replaces the line with the same id when reading.
+/// The whole log is read into memory when the file is opened
+pub struct FileHistory {
+    file: File,
+    rooms: HashMap<String, Vec<Message>>,
+}
+
+impl FileHistory {
+    /// Opens (or creates) the log file and reads the messages that are already in it
+    pub fn open(path: &Path) -> io::Result<FileHistory> {
+        let mut rooms : HashMap<String, Vec<Message>> = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    };
+                    // A half written line (e.g. after a crash) shouldn't stop the server from starting
+                    match serde_json::from_str::<LogEntry>(&line) {
+                        Ok(entry) => insert_or_replace(rooms.entry(entry.room).or_default(), entry.message),
+                        Err(err) => eprintln!("Skipping broken line in {}: {}", path.display(), err),
+                    };
+                };
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileHistory {
+            file,
+            rooms,
+        })
+    }
+
+
+    /// Writes the message as a new line at the end of the log
+    fn write_line(&mut self, room: &str, message: &Message) -> io::Result<()> {
+        let entry = LogEntry {
+            room: room.to_string(),
+            message: message.clone(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn append(&mut self, room: &str, message: &Message) -> io::Result<()> {
+        self.write_line(room, message)?;
+        self.rooms.entry(room.to_string()).or_default().push(message.clone());
+        Ok(())
+    }
+
+    fn messages<'a>(&'a self, room: &str) -> Box<dyn DoubleEndedIterator<Item = &'a Message> + 'a> {
+        match self.rooms.get(room) {
+            Some(messages) => Box::new(messages.iter()),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn rooms<'a>(&'a self) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        Box::new(self.rooms.keys().map(String::as_str))
+    }
+
+    fn last_id(&self) -> Option<MessageId> {
+        self.rooms.values().filter_map(|messages| messages.last()?.id).max()
+    }
+
+    fn replace(&mut self, room: &str, message: &Message) -> io::Result<bool> {
+        let stored = match (self.rooms.get(room), message.id) {
+            (Some(messages), Some(id)) => messages.iter().any(|stored| stored.id == Some(id)),
+            _ => false,
+        };
+        if !stored {
+            return Ok(false);
+        };
+        self.write_line(room, message)?;
+        insert_or_replace(self.rooms.entry(room.to_string()).or_default(), message.clone());
+        Ok(true)
+    }
+}
+
+
+/// Adds a message read from the log, unless it replaces one that was read before
+fn insert_or_replace(messages: &mut Vec<Message>, message: Message) {
+    let stored = messages.iter_mut().rev().find(|stored| stored.id.is_some() && stored.id == message.id);
+    match stored {
+        Some(stored) => *stored = message,
+        None => messages.push(message),
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A room with the messages 1 to `count`, oldest first
+    fn history(count: u64) -> MemoryHistory {
+        let mut history = MemoryHistory::new(100);
+        for id in 1..=count {
+            let message = Message {
+                text: format!("message {}", id),
+                id: Some(id),
+                seq: Some(id),
+                ..Message::new()
+            };
+            history.append("dev", &message).unwrap();
+        };
+        history
+    }
+
+    fn ids(messages: &[Message]) -> Vec<MessageId> {
+        messages.iter().filter_map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn before_pages_backwards() {
+        let history = history(10);
+        assert_eq!(ids(&history.recent("dev", 3)), [8, 9, 10]);
+        assert_eq!(ids(&history.before("dev", Some(8), 3)), [5, 6, 7]);
+        assert_eq!(ids(&history.before("dev", Some(3), 5)), [1, 2]);
+        assert!(history.before("dev", Some(1), 5).is_empty());
+        assert!(history.before("other", None, 5).is_empty());
+    }
+
+    #[test]
+    fn page_before_tells_if_more_are_left() {
+        let history = history(10);
+        let (messages, has_more) = history.page_before("dev", Some(8), 3);
+        assert_eq!((ids(&messages), has_more), (vec![5, 6, 7], true));
+        let (messages, has_more) = history.page_before("dev", Some(5), 4);
+        assert_eq!((ids(&messages), has_more), (vec![1, 2, 3, 4], false));
+        let (messages, has_more) = history.page_before("dev", None, 20);
+        assert_eq!((messages.len(), has_more), (10, false));
+    }
+
+    #[test]
+    fn around_centers_the_message() {
+        let history = history(10);
+        assert_eq!(ids(&history.around("dev", 5, 4)), [3, 4, 5, 6]);
+        // Without older messages the batch fills up with newer ones, but not the other way around
+        assert_eq!(ids(&history.around("dev", 1, 4)), [1, 2, 3, 4]);
+        assert_eq!(ids(&history.around("dev", 10, 4)), [8, 9, 10]);
+    }
+
+    #[test]
+    fn only_the_newest_messages_are_kept() {
+        let mut history = MemoryHistory::new(3);
+        for id in 1..=5 {
+            history.append("dev", &Message { id: Some(id), ..Message::new() }).unwrap();
+        };
+        assert_eq!(ids(&history.recent("dev", 10)), [3, 4, 5]);
+        assert_eq!(history.last_id(), Some(5));
+    }
+}
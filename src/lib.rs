@@ -11,9 +11,19 @@ pub mod message;
 pub mod peer;
 pub mod types;
 pub mod codec;
+pub mod accounts;
+pub mod config;
+pub mod history;
+pub mod search;
+pub mod protocol;
+pub mod command;
+pub mod permissions;
+pub mod reactions;
+pub mod mentions;
+pub mod transfer;
 
 
 /// Utility function to join the contents of 2 Strings together
 pub fn join_strings(str1: String, str2: String) -> String {
-    String::from(format!("{}{}", str1.as_str(), str2.as_str()))
+    format!("{}{}", str1.as_str(), str2.as_str())
 }
\ No newline at end of file
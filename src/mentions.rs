@@ -0,0 +1,48 @@
+use crate::peer::MAX_USERNAME_LEN;
+
+
+/// Whether the character can be part of a username
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+
+/// The names mentioned in the text with `@name`, in the order they first appear.
+/// An '@' right after a name character (like in an email address) doesn't start a mention
+pub fn parse_mentions(text: &str) -> Vec<&str> {
+    let mut mentions : Vec<&str> = Vec::new();
+    let mut previous = None;
+    for (i, c) in text.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let rest = &text[i + 1..];
+            let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            let name = &rest[..end];
+            let known = mentions.iter().any(|mentioned| mentioned.eq_ignore_ascii_case(name));
+            if !name.is_empty() && name.len() <= MAX_USERNAME_LEN && !known {
+                mentions.push(name);
+            };
+        };
+        previous = Some(c);
+    };
+    mentions
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mentions_in_order() {
+        assert_eq!(parse_mentions("@alice and @bob_2, what about @carol-x?"), ["alice", "bob_2", "carol-x"]);
+        assert_eq!(parse_mentions("@Alice @alice @ALICE"), ["Alice"]);
+    }
+
+    #[test]
+    fn ignores_what_isnt_a_mention() {
+        assert!(parse_mentions("mail me at alice@example.com").is_empty());
+        assert!(parse_mentions("@ @! @@").is_empty());
+        assert!(parse_mentions(&format!("@{}", "a".repeat(MAX_USERNAME_LEN + 1))).is_empty());
+        assert_eq!(parse_mentions("(@bob)"), ["bob"]);
+    }
+}
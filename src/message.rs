@@ -1,16 +1,62 @@
+use chrono::{DateTime, Utc};
 use serde_derive::{Serialize, Deserialize};
 
-use crate::types::MessageType;
+use crate::protocol::ProtocolError;
+use crate::types::{MessageType, MessageId, RequestId, UserId, Reactions};
 
 
 /// Represents a message that gets serialized and deserialized when being sent.
-/// The into implementations use serde (serde_json) to (de-)serialized the message
-/// into a string
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// The conversions use serde (serde_json) to (de-)serialize the message
+/// from and into a string. Parsing can fail, so it's a `TryFrom`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Message {
     pub text: String,
     pub msg_type: MessageType,
     pub author: String,
+    /// Name of the room (without the leading '#') this message is sent to or came from.
+    /// Messages that don't belong to any room (e.g. system prompts) leave this empty
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Username of the receiver of a private message
+    #[serde(default)]
+    pub recipient: Option<String>,
+    /// Set by the server on every message it delivers through the shared state
+    #[serde(default)]
+    pub id: Option<MessageId>,
+    /// When the server received the message
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Position of the message in its room, counting up by one with every message that gets
+    /// recorded in the room. A gap means messages were missed
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// Set by the client on a command it wants to know the outcome of
+    #[serde(default)]
+    pub request_id: Option<RequestId>,
+    /// Set by the server on messages sent to a room, so only the author gets to change them
+    #[serde(default)]
+    pub author_id: Option<UserId>,
+    /// When the text was last changed with an `Edit`
+    #[serde(default)]
+    pub edited: Option<DateTime<Utc>>,
+    /// Whether the message was removed with a `Delete`. Its text is gone then
+    #[serde(default)]
+    pub deleted: bool,
+    /// Who reacted to the message with what, kept up to date by the server
+    #[serde(default)]
+    pub reactions: Reactions,
+    /// The earlier message of the room this one answers
+    #[serde(default)]
+    pub reply_to: Option<MessageId>,
+    /// Id of the first message of the thread a reply belongs to, set by the server
+    #[serde(default)]
+    pub thread: Option<MessageId>,
+    /// How many replies the thread started by this message has, kept up to date by the server
+    #[serde(default)]
+    pub reply_count: usize,
+    /// Usernames of the members of the room the text mentions with `@name`, set by the server
+    #[serde(default)]
+    pub mentions: Vec<String>,
 }
 
 impl Message {
@@ -19,28 +65,67 @@ impl Message {
             text: String::new(),
             msg_type: MessageType::User,
             author: String::new(),
+            room: None,
+            recipient: None,
+            id: None,
+            timestamp: None,
+            seq: None,
+            request_id: None,
+            author_id: None,
+            edited: None,
+            deleted: false,
+            reactions: Reactions::new(),
+            reply_to: None,
+            thread: None,
+            reply_count: 0,
+            mentions: Vec::new(),
         }
     }
 }
 
-impl Into<std::string::String> for Message {
-    fn into(self) -> std::string::String {
-        serde_json::to_string(&self).unwrap()
+impl Default for Message {
+    fn default() -> Self {
+        Self::new()
     }
 }
-impl Into<std::string::String> for &Message {
-    fn into(self) -> std::string::String {
-        serde_json::to_string(self).unwrap()
+
+impl From<Message> for std::string::String {
+    fn from(message: Message) -> std::string::String {
+        serde_json::to_string(&message).unwrap()
+    }
+}
+impl From<&Message> for std::string::String {
+    fn from(message: &Message) -> std::string::String {
+        serde_json::to_string(message).unwrap()
     }
 }
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {}", self.author, self.text)
+        if let Some(room) = &self.room {
+            write!(f, "[#{}] ", room)?;
+        };
+        let text = if self.deleted {
+            String::from("(message deleted)")
+        } else if self.edited.is_some() {
+            format!("{} (edited)", self.text)
+        } else {
+            self.text.clone()
+        };
+        if let Some(recipient) = &self.recipient {
+            return write!(f, "{} -> {}: {}", self.author, recipient, text);
+        };
+        // Notes the client makes up itself don't have an author
+        if self.author.is_empty() {
+            return write!(f, "{}", text);
+        };
+        write!(f, "{}: {}", self.author, text)
     }
 }
-impl Into<Message> for String {
-    fn into(self) -> Message {
-        serde_json::from_str(self.as_str()).unwrap()
+impl TryFrom<&str> for Message {
+    type Error = ProtocolError;
+
+    fn try_from(line: &str) -> Result<Message, ProtocolError> {
+        Ok(serde_json::from_str(line)?)
     }
 }
 
@@ -1,11 +1,18 @@
-use tokio::sync::{Mutex, MutexGuard, mpsc};
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::io;
 
-use crate::shared::Shared;
 use crate::codec::Codec;
-use crate::Reciever;
+use crate::protocol::Capability;
+use crate::types::{UserId, ConnectionId, Status};
+use crate::{Transmitter, Reciever};
+
+
+/// Maximum amount of characters a username may have
+pub const MAX_USERNAME_LEN : usize = 20;
+/// Maximum amount of characters a status text may have
+pub const MAX_STATUS_LEN : usize = 60;
+/// Names that can't be taken because the server uses them itself
+pub const RESERVED_USERNAMES : [&str; 1] = ["system"];
 
 
 /// A single peer that is connected to the server.
@@ -14,30 +21,100 @@ use crate::Reciever;
 pub struct Peer {
     pub codec: Codec,
     pub reciever: Reciever,
+    /// What the client and the server agreed on in the handshake
+    pub capabilities: BTreeSet<Capability>,
 }
 
 impl Peer {
-    pub async fn new(
-        state: Arc<Mutex<Shared>>,
-        codec: Codec,
-        user: &User,
-    ) -> io::Result<Peer> {
-        let (transmitter, reciever) = mpsc::unbounded_channel();
-        let mut guard : MutexGuard<Shared> = state.lock().await;
-        guard.peers.insert(user.clone(), transmitter);
-        std::mem::drop(guard);
-
-        Ok(Peer {
+    /// The reciever has to come from registering the user with `Shared::add_peer`
+    pub fn new(codec: Codec, reciever: Reciever, capabilities: BTreeSet<Capability>) -> Peer {
+        Peer {
             codec,
             reciever,
-        })
+            capabilities,
+        }
+    }
+
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
     }
 }
 
 
 /// A single User object
-#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct User {
+    /// Identity of the user, everything on the server refers to the user by it
+    pub id: UserId,
+    /// The connection the user is currently using
+    pub conn: ConnectionId,
+    /// Where the current connection comes from, for logging and for address bans (see `Chat::is_banned`).
+    /// It may change between connections
     pub addr: SocketAddr,
     pub usrname: String,
-}
\ No newline at end of file
+}
+
+
+/// What the shared state keeps for each connected user:
+/// the user itself and the way to reach its connection task
+pub struct PeerHandle {
+    pub user: User,
+    pub transmitter: Transmitter,
+    /// What the client and the server agreed on in the handshake
+    pub capabilities: BTreeSet<Capability>,
+    /// Whether the user is around, as set with /status
+    pub status: Status,
+}
+
+
+/// Reasons why a username can't be used
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum UsernameError {
+    Empty,
+    TooLong,
+    InvalidCharacters,
+    Reserved,
+    Taken(String),
+    AlreadyLoggedIn(String),
+    /// Accounts can't be renamed to or from the names of the admins in the server config
+    AdminName(String),
+    /// The account couldn't be saved under the new name, so it keeps the old one
+    NotSaved,
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "The username can't be empty"),
+            UsernameError::TooLong => write!(f, "The username can't be longer than {} characters", MAX_USERNAME_LEN),
+            UsernameError::InvalidCharacters => write!(f, "The username may only contain letters, digits, '-' and '_'"),
+            UsernameError::Reserved => write!(f, "That username is reserved"),
+            UsernameError::Taken(name) => write!(f, "The username {} is already taken", name),
+            UsernameError::AlreadyLoggedIn(name) => write!(f, "{} is already logged in", name),
+            UsernameError::AdminName(name) => write!(f, "{} is an admin of the server, accounts can't be renamed to or from that name", name),
+            UsernameError::NotSaved => write!(f, "Could not save the new name of your account, please try again later"),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+
+/// Checks that the username only uses allowed characters and isn't too long.
+/// Whether the name is already taken is checked by `Shared::add_peer`/`Shared::rename_peer`
+pub fn validate_username(name: &str) -> Result<(), UsernameError> {
+    if name.is_empty() {
+        return Err(UsernameError::Empty);
+    };
+    if name.chars().count() > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong);
+    };
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(UsernameError::InvalidCharacters);
+    };
+    if RESERVED_USERNAMES.contains(&name.to_lowercase().as_str()) {
+        return Err(UsernameError::Reserved);
+    };
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use serde_derive::{Serialize, Deserialize};
+
+
+/// What a user is on the server, or in a single room if it overrides the role there.
+/// Every role can do everything the roles before it can
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub enum Role {
+    /// Users without an account
+    Guest,
+    /// Every registered user, unless given another role
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    /// Reads a role the way users type it, e.g. in /role
+    pub fn parse(text: &str) -> Option<Role> {
+        match text.to_lowercase().as_str() {
+            "guest" => Some(Role::Guest),
+            "member" => Some(Role::Member),
+            "moderator" | "mod" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+
+    /// The role with its article, e.g. "an admin"
+    pub fn with_article(&self) -> String {
+        match self {
+            Role::Admin => format!("an {}", self),
+            _ => format!("a {}", self),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Guest => write!(f, "guest"),
+            Role::Member => write!(f, "member"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+
+/// Something a user needs to be allowed to, checked before every command and action.
+/// Each permission comes with the lowest role that has it, which the server config may change
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Permission {
+    SendMessages,
+    /// Posting in read-only announcement rooms
+    PostAnnouncements,
+    SendPrivateMessages,
+    CreateRooms,
+    JoinRooms,
+    React,
+    SendFiles,
+    ChangeNick,
+    /// Kicking, banning and muting members and deleting the messages of others
+    ModerateRooms,
+    /// Making rooms read-only
+    ManageRooms,
+    /// Giving users another role on the whole server
+    ManageRoles,
+}
+
+impl Permission {
+    /// The lowest role that has the permission, unless the server config says otherwise
+    pub fn default_role(&self) -> Role {
+        match self {
+            Permission::SendMessages
+                | Permission::SendPrivateMessages
+                | Permission::CreateRooms
+                | Permission::JoinRooms
+                | Permission::React
+                | Permission::SendFiles
+                | Permission::ChangeNick => Role::Guest,
+            Permission::PostAnnouncements
+                | Permission::ModerateRooms
+                | Permission::ManageRooms => Role::Moderator,
+            Permission::ManageRoles => Role::Admin,
+        }
+    }
+
+
+    /// Whether the role of the user in a room counts for the permission there. The others are
+    /// about the whole server, so room roles can't grant them
+    pub fn in_rooms(&self) -> bool {
+        match self {
+            Permission::SendMessages
+                | Permission::PostAnnouncements
+                | Permission::React
+                | Permission::SendFiles
+                | Permission::ModerateRooms
+                | Permission::ManageRooms => true,
+            Permission::SendPrivateMessages
+                | Permission::CreateRooms
+                | Permission::JoinRooms
+                | Permission::ChangeNick
+                | Permission::ManageRoles => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::SendMessages => write!(f, "send messages"),
+            Permission::PostAnnouncements => write!(f, "post in read-only rooms"),
+            Permission::SendPrivateMessages => write!(f, "send private messages"),
+            Permission::CreateRooms => write!(f, "create rooms"),
+            Permission::JoinRooms => write!(f, "join rooms"),
+            Permission::React => write!(f, "react to messages"),
+            Permission::SendFiles => write!(f, "send files"),
+            Permission::ChangeNick => write!(f, "change your username"),
+            Permission::ModerateRooms => write!(f, "moderate rooms"),
+            Permission::ManageRooms => write!(f, "manage rooms"),
+            Permission::ManageRoles => write!(f, "give out roles"),
+        }
+    }
+}
+
+
+/// Reasons why a user may not do something or give someone a role
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PermissionError {
+    /// The user's role is below the one the permission needs
+    Denied { permission: Permission, required: Role },
+    /// Nobody can hand out a role above their own
+    AboveOwnRole(Role),
+    /// The role of the user can't be changed by whoever tried, holds the username
+    Protected(String),
+    /// Server wide roles are kept with the account, so guests can't get one. Holds the username
+    NoAccount(String),
+}
+
+impl std::fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermissionError::Denied { permission, required } => write!(f, "You need to be {} or higher to {}", required.with_article(), permission),
+            PermissionError::AboveOwnRole(role) => write!(f, "You can't make anyone {}, as that is above your own role", role.with_article()),
+            PermissionError::Protected(username) => write!(f, "You can't change the role of {}", username),
+            PermissionError::NoAccount(username) => write!(f, "{} has no account", username),
+        }
+    }
+}
+
+impl std::error::Error for PermissionError {}
@@ -0,0 +1,108 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use serde_derive::{Serialize, Deserialize};
+
+
+/// Version of the message layout this build speaks. Bump it whenever `Message` or
+/// `MessageType` change in a way older builds can't read.
+/// Version 3 added request ids and `CommandResult`, version 4 `Edit` and `Delete`, version 5 reactions
+/// and version 6 threads. Both sides have to speak the same version, optional features are
+/// agreed on with capabilities instead
+pub const PROTOCOL_VERSION : u32 = 6;
+
+
+/// Optional features a client or server may support.
+/// Both sides announce theirs in the handshake and only the ones both have get used
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Capability {
+    /// Replaying and paging through older messages of a room
+    History,
+    /// Full text search with /search
+    Search,
+    /// Showing who is typing right now, with `MessageType::Typing`
+    Typing,
+    /// Delivery and read receipts, with `MessageType::Receipt` and `MessageType::Receipts`
+    Receipts,
+    /// Seeing who is online, away or busy, with `MessageType::PresenceUpdate`
+    Presence,
+    /// Sending files with /send and /accept
+    FileTransfer,
+}
+
+
+/// Fails unless the other side speaks the same version as this build
+pub fn check_version(theirs: u32) -> Result<u32, ProtocolError> {
+    if theirs != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(theirs));
+    };
+    Ok(theirs)
+}
+
+
+/// The capabilities both sides support
+pub fn negotiate_capabilities(ours: &[Capability], theirs: &BTreeSet<Capability>) -> BTreeSet<Capability> {
+    ours.iter().filter(|capability| theirs.contains(capability)).copied().collect()
+}
+
+
+/// Everything that can go wrong when reading or writing messages on a connection
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The line could not be parsed into a message, the reason is attached
+    Malformed(String),
+    /// The line was longer than `codec::MAX_LINE_LEN`. The connection can't be read from after this
+    LineTooLong,
+    /// The connection broke or was closed
+    Io(io::Error),
+    /// The other side speaks another protocol version
+    UnsupportedVersion(u32),
+    /// The connection didn't start with a `Hello`
+    MissingHello,
+    /// A message of a type only the other side may send, or only during the handshake or login
+    Unexpected,
+}
+
+impl ProtocolError {
+    /// Whether the connection can still be used after this error.
+    /// A line that isn't a valid message only costs that line, anything else ends the connection
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ProtocolError::Malformed(_) | ProtocolError::Unexpected => true,
+            ProtocolError::LineTooLong
+            | ProtocolError::Io(_)
+            | ProtocolError::UnsupportedVersion(_)
+            | ProtocolError::MissingHello => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Malformed(reason) => write!(f, "Malformed message: {}", reason),
+            ProtocolError::LineTooLong => write!(f, "The message is too long"),
+            ProtocolError::Io(err) => write!(f, "Connection error: {}", err),
+            ProtocolError::UnsupportedVersion(version) => write!(
+                f, "Protocol version {} is not supported, only version {} is. Please update",
+                version, PROTOCOL_VERSION,
+            ),
+            ProtocolError::MissingHello => write!(f, "The connection has to start with a Hello. Please update your client"),
+            ProtocolError::Unexpected => write!(f, "This kind of message can't be sent now"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(err: serde_json::Error) -> Self {
+        ProtocolError::Malformed(err.to_string())
+    }
+}
@@ -0,0 +1,64 @@
+/// Shortcodes that can be typed instead of the emoji itself, e.g. `/react 12 :tada:`
+pub const SHORTCODES : [(&str, &str); 16] = [
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("tada", "🎉"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("rocket", "🚀"),
+    ("thinking", "🤔"),
+    ("check", "✅"),
+    ("x", "❌"),
+    ("pray", "🙏"),
+    ("100", "💯"),
+];
+
+/// Most characters a single reaction may have. Some emoji are made of several characters
+pub const MAX_REACTION_LEN : usize = 8;
+
+
+/// Reasons why something can't be used as a reaction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ReactionError {
+    UnknownShortcode(String),
+    /// Reactions are emoji, not words
+    Invalid(String),
+}
+
+impl std::fmt::Display for ReactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReactionError::UnknownShortcode(code) => {
+                let known : Vec<String> = SHORTCODES.iter().map(|(code, _)| format!(":{}:", code)).collect();
+                write!(f, "Unknown shortcode :{}:, known ones are {}", code, known.join(" "))
+            },
+            ReactionError::Invalid(reaction) => write!(f, "'{}' is not an emoji", reaction),
+        }
+    }
+}
+
+impl std::error::Error for ReactionError {}
+
+
+/// Turns what the user typed into the reaction to store: shortcodes become their emoji,
+/// anything else has to look like an emoji already
+pub fn parse_reaction(input: &str) -> Result<String, ReactionError> {
+    let input = input.trim();
+    if let Some(code) = input.strip_prefix(':').and_then(|code| code.strip_suffix(':')) {
+        let code = code.to_lowercase();
+        return SHORTCODES.iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, emoji)| emoji.to_string())
+            .ok_or(ReactionError::UnknownShortcode(code));
+    };
+    let looks_like_emoji = input.chars().all(|c| !c.is_ascii() && !c.is_whitespace() && !c.is_alphanumeric());
+    if input.is_empty() || input.chars().count() > MAX_REACTION_LEN || !looks_like_emoji {
+        return Err(ReactionError::Invalid(input.to_string()));
+    };
+    Ok(input.to_string())
+}
@@ -0,0 +1,105 @@
+use serde_derive::{Serialize, Deserialize};
+
+use crate::message::Message;
+
+
+/// What a /search is looking for.
+/// The filters `in #room` and `from <user>` can be put after the search words
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct SearchQuery {
+    /// Lowercase words that all have to appear in a message
+    pub terms: Vec<String>,
+    /// Only search this room (name without the leading '#')
+    pub room: Option<String>,
+    /// Only find messages written by this user
+    pub from: Option<String>,
+}
+
+
+/// Reasons why the arguments of /search can't be used
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SearchError {
+    EmptyQuery,
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::EmptyQuery => write!(f, "Usage: /search <query> [in #room] [from user]"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+
+impl SearchQuery {
+    /// Parses the arguments of /search. The filters are only recognized at the end,
+    /// so "/search meeting in the morning" still searches for all four words
+    pub fn parse(args: &[&str]) -> Result<SearchQuery, SearchError> {
+        let mut args = args.iter().copied().filter(|arg| !arg.is_empty()).collect::<Vec<&str>>();
+        let mut room = None;
+        let mut from = None;
+        while args.len() >= 2 {
+            let value = args[args.len() - 1];
+            match args[args.len() - 2] {
+                "in" if room.is_none() && value.starts_with('#') => room = Some(value.trim_start_matches('#').to_lowercase()),
+                "from" if from.is_none() => from = Some(value.to_string()),
+                _ => break,
+            };
+            args.truncate(args.len() - 2);
+        };
+        if args.is_empty() {
+            return Err(SearchError::EmptyQuery);
+        };
+        Ok(SearchQuery {
+            terms: args.iter().map(|arg| arg.to_lowercase()).collect(),
+            room,
+            from,
+        })
+    }
+
+
+    /// How well the message matches the query, or None if it doesn't match at all.
+    /// Every word has to appear somewhere in the text, whole words count more than
+    /// parts of words and finding all words in a row counts the most
+    pub fn score(&self, message: &Message) -> Option<usize> {
+        if let Some(from) = &self.from {
+            if !message.author.eq_ignore_ascii_case(from) {
+                return None;
+            };
+        };
+        let text = message.text.to_lowercase();
+        let words : Vec<&str> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        let mut score = 0;
+        for term in self.terms.iter() {
+            let found = text.matches(term.as_str()).count();
+            if found == 0 {
+                return None;
+            };
+            let whole_words = words.iter().filter(|word| **word == term.as_str()).count();
+            score += found + 2 * whole_words;
+        };
+        if self.terms.len() > 1 && text.contains(&self.terms.join(" ")) {
+            score += 5;
+        };
+        Some(score)
+    }
+}
+
+
+impl std::fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.terms.join(" "))?;
+        if let Some(room) = &self.room {
+            write!(f, " in #{}", room)?;
+        };
+        if let Some(from) = &self.from {
+            write!(f, " from {}", from)?;
+        };
+        Ok(())
+    }
+}
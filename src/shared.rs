@@ -1,126 +1,1244 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
 
-use crate::peer::User;
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::mpsc;
+
+use crate::peer::{User, PeerHandle, UsernameError, validate_username};
+use crate::accounts::AccountStore;
+use crate::config::ServerConfig;
+use crate::history::{HistoryStore, MemoryHistory};
 use crate::message::Message;
-use crate::codec::Codec;
-use crate::Transmitter;
+use crate::protocol::Capability;
+use crate::mentions;
+use crate::permissions::{Permission, PermissionError, Role};
+use crate::transfer::{self, Transfer, TransferTarget, TransferError, FileInfo, Assembly};
+use crate::types::{MessageType, RoomId, UserId, ConnectionId, MessageId, Receipt, Presence, Status, TransferId};
+use crate::Reciever;
+
 
+/// Maximum amount of characters a room name may have
+pub const MAX_ROOM_NAME_LEN : usize = 32;
 
 
 /// Represents a single chat room instance on the server
-/// Might hold chat name/ description, mods etc. later
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Chat {
-    pub members: Vec<SocketAddr>,
+    pub id: RoomId,
+    /// Name of the room without the leading '#'
+    pub name: String,
+    pub topic: String,
+    pub created_at: DateTime<Utc>,
+    pub members: Vec<UserId>,
+    /// Who created the room. Rooms made by joining another user don't have an owner
+    pub owner: Option<UserId>,
+    /// Members the owner made operators with /op. Together with the owner they moderate the room
+    pub operators: BTreeSet<UserId>,
+    /// Who may not join the room anymore. Keyed by user, so renaming doesn't get anyone out of a ban
+    pub bans: BTreeMap<UserId, Ban>,
+    /// Members that may not send messages to the room, until the time if there is one
+    pub muted: BTreeMap<UserId, Option<DateTime<Utc>>>,
+    /// Roles users have in this room instead of their server wide one
+    pub roles: BTreeMap<UserId, Role>,
+    /// Announcement rooms, only users with `Permission::PostAnnouncements` may send messages to them
+    pub read_only: bool,
+    /// Sequence number of the last message that was broadcast to the room
+    pub last_seq: u64,
+}
+
+impl Chat {
+    pub fn new(id: RoomId, name: String, topic: String) -> Chat {
+        Chat {
+            id,
+            name,
+            topic,
+            created_at: Utc::now(),
+            members: Vec::new(),
+            owner: None,
+            operators: BTreeSet::new(),
+            bans: BTreeMap::new(),
+            muted: BTreeMap::new(),
+            roles: BTreeMap::new(),
+            read_only: false,
+            last_seq: 0,
+        }
+    }
+
+
+    /// Whether the user is the owner or an operator of the room, which makes them a moderator in it
+    pub fn is_moderator(&self, user: UserId) -> bool {
+        self.owner == Some(user) || self.operators.contains(&user)
+    }
+
+
+    /// Whether the user, or anyone connecting from the same address, was banned from the room
+    pub fn is_banned(&self, user: &User) -> bool {
+        self.bans.values().any(|ban| ban.user == user.id || ban.addr == Some(user.addr.ip()))
+    }
+
+
+    /// Whether the user may not send messages to the room right now
+    pub fn is_muted(&self, user: UserId, now: DateTime<Utc>) -> bool {
+        match self.muted.get(&user) {
+            Some(Some(until)) => *until > now,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+
+    /// Whether the room was moderated in a way that has to outlive its members. Such rooms are
+    /// kept once everyone left, so nobody can get rid of a ban by creating the room again
+    pub fn is_moderated(&self, now: DateTime<Utc>) -> bool {
+        !self.operators.is_empty()
+            || !self.bans.is_empty()
+            || !self.roles.is_empty()
+            || self.read_only
+            || self.muted.keys().any(|user| self.is_muted(*user, now))
+    }
+
+
+    /// Whether the room can be removed because nobody is in it and nothing about it has to be kept
+    pub fn is_abandoned(&self, now: DateTime<Utc>) -> bool {
+        self.members.is_empty() && !self.is_moderated(now)
+    }
+}
+
+
+/// Someone who was banned from a room
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Ban {
+    pub user: UserId,
+    /// Name the user had when they were banned, for guests that can't be looked up anymore
+    pub username: String,
+    /// Keeps out everyone connecting from this address as well, e.g. the same person as a new guest
+    pub addr: Option<IpAddr>,
+}
+
+impl std::fmt::Display for Chat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} ({} member(s), created {})", self.name, self.members.len(), self.created_at.format("%Y-%m-%d %H:%M UTC"))?;
+        if self.read_only {
+            write!(f, " (read-only)")?;
+        };
+        if !self.topic.is_empty() {
+            write!(f, " - {}", self.topic)?;
+        };
+        Ok(())
+    }
+}
+
+
+/// Everything that can go wrong when creating, joining or leaving a room
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RoomError {
+    InvalidName(String),
+    AlreadyExists(String),
+    NotFound(String),
+    AlreadyMember(String),
+    NotMember(String),
+    NoActiveRoom,
+    Banned(String),
+    Muted(String),
+    /// Only the moderators of the room may do that, holds the name of the room
+    NotModerator(String),
+    NotOwner(String),
+    /// The moderator may not kick, ban or mute that user (e.g. the owner), holds the username
+    Protected(String),
+    /// The user isn't in the room (or not connected at all), holds the username
+    UnknownMember(String),
+    NotBanned(String),
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomError::InvalidName(name) => write!(f, "'{}' is not a valid room name (letters, digits, '-' and '_', at most {} characters)", name, MAX_ROOM_NAME_LEN),
+            RoomError::AlreadyExists(name) => write!(f, "Room #{} already exists", name),
+            RoomError::NotFound(name) => write!(f, "Room #{} doesn't exist", name),
+            RoomError::AlreadyMember(name) => write!(f, "You are already in #{}", name),
+            RoomError::NotMember(name) => write!(f, "You are not in #{}", name),
+            RoomError::NoActiveRoom => write!(f, "You are not in any room. Type /join #<room> or /create #<room> first"),
+            RoomError::Banned(name) => write!(f, "You are banned from #{}", name),
+            RoomError::Muted(name) => write!(f, "You are muted in #{} and can't send messages there", name),
+            RoomError::NotModerator(name) => write!(f, "Only the moderators of #{} can do that", name),
+            RoomError::NotOwner(name) => write!(f, "Only the owner of #{} can do that", name),
+            RoomError::Protected(username) => write!(f, "You can't do that to {}", username),
+            RoomError::UnknownMember(username) => write!(f, "{} is not in the room", username),
+            RoomError::NotBanned(username) => write!(f, "{} is not banned", username),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+
+/// Reasons why a message can't be edited or deleted
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MessageError {
+    /// The room doesn't have a message with the id (anymore)
+    NotFound(MessageId),
+    /// Only the author may edit a message, and only the author or a moderator delete it
+    NotAllowed,
+    AlreadyDeleted,
+    EmptyText,
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::NotFound(id) => write!(f, "Message {} doesn't exist (anymore)", id),
+            MessageError::NotAllowed => write!(f, "You can only change your own messages"),
+            MessageError::AlreadyDeleted => write!(f, "The message was deleted"),
+            MessageError::EmptyText => write!(f, "The new text can't be empty, use /delete to remove the message"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+
+/// Strips the optional leading '#' of a room name and checks that the rest is valid
+pub fn normalize_room_name(name: &str) -> Result<String, RoomError> {
+    let stripped = name.strip_prefix('#').unwrap_or(name);
+    let valid_chars = stripped.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if stripped.is_empty() || stripped.len() > MAX_ROOM_NAME_LEN || !valid_chars {
+        return Err(RoomError::InvalidName(name.to_string()));
+    };
+    Ok(stripped.to_lowercase())
 }
 
 
 /// Data that is shared between each user
 pub struct Shared {
-    pub peers: HashMap<User, Transmitter>,
+    pub peers: HashMap<UserId, PeerHandle>,
     pub chats: Vec<Chat>,
+    /// The room each connected user is currently talking in
+    pub active_rooms: HashMap<UserId, RoomId>,
+    pub accounts: AccountStore,
+    pub history: Box<dyn HistoryStore>,
+    pub config: ServerConfig,
+    /// How far each user got in each room, by room name so it outlives the room
+    receipts: HashMap<UserId, HashMap<String, Receipt>>,
+    /// How many messages mentioned each user since they last looked at their mentions
+    unread_mentions: HashMap<UserId, usize>,
+    /// Files that are being uploaded or wait to be downloaded
+    transfers: HashMap<TransferId, Transfer>,
+    next_room_id: RoomId,
+    next_message_id: MessageId,
+    next_user_id: u64,
+    next_connection_id: u64,
+    next_transfer_id: TransferId,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shared {
     pub fn new() -> Self {
+        let config = ServerConfig::default();
+        let history = Box::new(MemoryHistory::new(config.history_capacity));
+        Shared::with_config(config, AccountStore::in_memory(), history)
+    }
+
+
+    pub fn with_config(config: ServerConfig, accounts: AccountStore, history: Box<dyn HistoryStore>) -> Self {
+        // Guests must never get the id of a registered account, or of anyone whose messages are still stored
+        let next_user_id = accounts.max_id().max(history.max_user_id()).map_or(1, |id| id.0 + 1);
+        let next_message_id = history.last_id().map_or(1, |id| id + 1);
         Shared {
             peers: HashMap::new(),
             chats: Vec::<Chat>::new(),
+            active_rooms: HashMap::new(),
+            accounts,
+            history,
+            config,
+            receipts: HashMap::new(),
+            unread_mentions: HashMap::new(),
+            transfers: HashMap::new(),
+            next_room_id: 1,
+            next_message_id,
+            next_user_id,
+            next_connection_id: 1,
+            next_transfer_id: 1,
         }
     }
 
 
-    pub fn get_usr_from_addr(&self, addr: SocketAddr) -> Option<&User> {
-        let mut user = None;
-        for p in self.peers.iter() {
-            if p.0.addr == addr {
-                user = Some(p.0);
-                break;
-            }
-        };
-        user
+    pub fn get_usr(&self, id: UserId) -> Option<&User> {
+        self.peers.get(&id).map(|peer| &peer.user)
     }
 
 
+    /// Usernames are unique regardless of their case, so the lookup ignores it too
     pub fn get_usr_from_name(&self, name: String) -> Option<&User> {
-        let mut user = None;
-        for p in self.peers.iter() {
-            if p.0.usrname == name {
-                user = Some(p.0);
-                break;
-            }
+        self.peers.values()
+            .map(|peer| &peer.user)
+            .find(|user| user.usrname.eq_ignore_ascii_case(&name))
+    }
+
+
+    /// Hands out a new id for a user the server hasn't seen before
+    pub fn next_user_id(&mut self) -> UserId {
+        let id = UserId(self.next_user_id);
+        self.next_user_id += 1;
+        id
+    }
+
+
+    /// Hands out a new id for a freshly accepted connection
+    pub fn next_connection_id(&mut self) -> ConnectionId {
+        let id = ConnectionId(self.next_connection_id);
+        self.next_connection_id += 1;
+        id
+    }
+
+
+    /// Checks that the name is valid and neither used by another connected user
+    /// nor by an account that doesn't belong to the user
+    pub fn check_username_available(&self, user: UserId, name: &str) -> Result<(), UsernameError> {
+        validate_username(name)?;
+        if let Some(other) = self.get_usr_from_name(name.to_string()) {
+            if other.id != user {
+                return Err(UsernameError::Taken(name.to_string()));
+            };
         };
-        user
+        if let Some(account) = self.accounts.get(name) {
+            if account.id != user {
+                return Err(UsernameError::Taken(name.to_string()));
+            };
+        };
+        Ok(())
+    }
+
+
+    /// Registers a newly connected user, if the username is valid and not taken yet.
+    /// Returns the reciever for all messages that get sent to this user
+    pub fn add_peer(&mut self, user: User, capabilities: BTreeSet<Capability>) -> Result<Reciever, UsernameError> {
+        self.check_username_available(user.id, &user.usrname)?;
+        // The same account might be logged in already
+        if self.peers.contains_key(&user.id) {
+            return Err(UsernameError::AlreadyLoggedIn(user.usrname));
+        };
+        let (transmitter, reciever) = mpsc::unbounded_channel();
+        self.peers.insert(user.id, PeerHandle { user, transmitter, capabilities, status: Status::default() });
+        Ok(reciever)
     }
 
-    /// Sends the message to all users present in the chat
-    /// Can send a custom sender message to current client
-    pub async fn broadcast(&mut self, sender: SocketAddr, message: &Message, custom_sender_msg: &Message, codec: &mut Codec) {
-        let user_chats = self.get_chats_from_user_addr(sender).clone();
-        for chat in user_chats {
-            for peer in self.peers.iter() {
-                // peer is part of the currently afftected chat
-                if chat.members.contains(&peer.0.addr) {
-                    // This is the sender, so send him a custom message
-                    if custom_sender_msg.text.len() > 0 && peer.0.addr == sender {
-                        let send : String = custom_sender_msg.into();
-                        if send.len() > 0 {
-                            let _ = peer.1.send(send);
-                        }
-                        //codec.send_message(custom_sender_msg).await;
-                    // Not the sender
-                    } else if peer.0.addr != sender {
-                        let send : String = message.into();
-                        if send.len() > 0 {
-                            let _ = peer.1.send(send);
-                        }
-                        //codec.send_message(message).await;
-                    }
-                }
+
+    /// Changes the username of a connected user. Returns the renamed user
+    pub fn rename_peer(&mut self, user: &User, new_name: &str) -> Result<User, UsernameError> {
+        // Only changing the case of the own name is fine
+        self.check_username_available(user.id, new_name)?;
+        // Admins are known by name, so renaming would hand their role to whoever takes the name next
+        if self.accounts.get_by_id(user.id).is_some() && !user.usrname.eq_ignore_ascii_case(new_name) {
+            if let Some(name) = [user.usrname.as_str(), new_name].into_iter().find(|name| self.is_admin_name(name)) {
+                return Err(UsernameError::AdminName(name.to_string()));
+            };
+        };
+        if let Err(err) = self.accounts.rename(user.id, new_name) {
+            eprintln!("Could not rename the account of {}: {}", user.usrname, err);
+            return Err(UsernameError::NotSaved);
+        };
+        let renamed = User {
+            usrname: new_name.to_string(),
+            ..user.clone()
+        };
+        if let Some(peer) = self.peers.get_mut(&user.id) {
+            peer.user = renamed.clone();
+        };
+        Ok(renamed)
+    }
+
+
+    /// Sends the message exactly once to every member of the given room and records it in the history
+    /// Can send a custom sender message (with its request id) to current client. Fails if the sender is muted in the room
+    pub async fn broadcast(&mut self, sender: UserId, room: RoomId, message: &Message, custom_sender_msg: &Message) -> Result<(), RoomError> {
+        // Not using get_mut_chat here, as that would borrow all of self and not just the chats
+        let chat = match self.chats.iter_mut().find(|chat| chat.id == room) {
+            Some(chat) => chat,
+            None => return Ok(()),
+        };
+        if chat.is_muted(sender, Utc::now()) {
+            return Err(RoomError::Muted(chat.name.clone()));
+        };
+        chat.last_seq += 1;
+        // Stamp the room, id, time and sequence number onto the messages so clients know where they belong
+        let mut message = message.clone();
+        message.room = Some(chat.name.clone());
+        message.id = Some(self.next_message_id);
+        message.timestamp = Some(Utc::now());
+        message.seq = Some(chat.last_seq);
+        message.author_id = Some(sender);
+        self.next_message_id += 1;
+        let custom_sender_msg = Message {
+            text: custom_sender_msg.text.clone(),
+            request_id: custom_sender_msg.request_id,
+            ..message.clone()
+        };
+        if let Err(err) = self.history.append(&chat.name, &message) {
+            eprintln!("Could not record message in #{}: {}", chat.name, err);
+        };
+
+        for member in chat.members.iter() {
+            let peer = match self.peers.get(member) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            // This is the sender, so send him a custom message
+            if !custom_sender_msg.text.is_empty() && *member == sender {
+                let send : String = (&custom_sender_msg).into();
+                let _ = peer.transmitter.send(send);
+            // Not the sender
+            } else if *member != sender {
+                let send : String = (&message).into();
+                let _ = peer.transmitter.send(send);
             }
         }
+        Ok(())
+    }
+
 
+    /// Changes the text of a message in the room and records the change in the history.
+    /// Users the new text mentions for the first time get an unread mention.
+    /// Only the author may do that. Returns the changed message
+    pub fn edit_message(&mut self, editor: UserId, room: RoomId, id: MessageId, text: String) -> Result<Message, MessageError> {
+        if text.trim().is_empty() {
+            return Err(MessageError::EmptyText);
+        };
+        let mut message = self.get_changeable_message(room, id)?;
+        if message.author_id != Some(editor) {
+            return Err(MessageError::NotAllowed);
+        };
+        let mentions = self.resolve_mentions(room, &text);
+        let added : Vec<String> = mentions.iter()
+            .filter(|name| !message.mentions.iter().any(|mentioned| mentioned.eq_ignore_ascii_case(name)))
+            .cloned()
+            .collect();
+        self.add_unread_mentions(editor, &added);
+        message.mentions = mentions;
+        message.text = text;
+        message.edited = Some(Utc::now());
+        self.record_change(room, &message);
+        Ok(message)
     }
 
 
-    /// Joins the chat based on another users username
-    pub fn join_chat(&mut self, joining_user: &User, joined_user: &String) {
-        let other_user = self.get_usr_from_name(joined_user.clone());
-        match other_user {
-            Some(user) => {
-                let other_user_addr = user.addr;
-                let mut other_user_in_chat = false;
-                for chat in self.chats.iter_mut() {
-                    // Other user is already part of a chat
-                    if chat.members.contains(&other_user_addr) {
-                        chat.members.push(joining_user.addr);
-                        println!("{} is already part of chat: {:?}", joined_user, chat);
-                        other_user_in_chat = true;
-                    }
+    /// Removes the text of a message in the room, leaving a tombstone in the history.
+    /// The author and the moderators of the room may do that. Returns the deleted message
+    pub fn delete_message(&mut self, user: UserId, room: RoomId, id: MessageId) -> Result<Message, MessageError> {
+        let mut message = self.get_changeable_message(room, id)?;
+        let moderator = self.check_permission(user, Some(room), Permission::ModerateRooms).is_ok();
+        if message.author_id != Some(user) && !moderator {
+            return Err(MessageError::NotAllowed);
+        };
+        message.text.clear();
+        message.deleted = true;
+        self.record_change(room, &message);
+        Ok(message)
+    }
+
+
+    /// Adds the reaction of the user to a message in the room, or takes it back if it was there already.
+    /// Returns the message with its reactions now
+    pub fn toggle_reaction(&mut self, user: UserId, room: RoomId, id: MessageId, reaction: String) -> Result<Message, MessageError> {
+        let mut message = self.get_changeable_message(room, id)?;
+        let users = message.reactions.entry(reaction.clone()).or_default();
+        if !users.remove(&user) {
+            users.insert(user);
+        };
+        if users.is_empty() {
+            message.reactions.remove(&reaction);
+        };
+        self.record_change(room, &message);
+        Ok(message)
+    }
+
+
+    /// The members of the room the text mentions, by their actual username
+    pub fn resolve_mentions(&self, room: RoomId, text: &str) -> Vec<String> {
+        let chat = match self.get_chat(room) {
+            Some(chat) => chat,
+            None => return Vec::new(),
+        };
+        mentions::parse_mentions(text).into_iter()
+            .filter_map(|name| self.get_usr_from_name(name.to_string()))
+            .filter(|user| chat.members.contains(&user.id))
+            .map(|user| user.usrname.clone())
+            .collect()
+    }
+
+
+    /// Counts an unread mention for everyone a message of the author mentions, except the author
+    pub fn add_unread_mentions(&mut self, author: UserId, mentions: &[String]) {
+        for name in mentions {
+            let mentioned = match self.get_usr_from_name(name.clone()) {
+                Some(user) if user.id != author => user.id,
+                _ => continue,
+            };
+            *self.unread_mentions.entry(mentioned).or_default() += 1;
+        };
+    }
+
+
+    /// How many mentions the user hasn't looked at yet
+    pub fn unread_mentions(&self, user: UserId) -> usize {
+        self.unread_mentions.get(&user).copied().unwrap_or(0)
+    }
+
+
+    /// Marks all mentions of the user as read, e.g. after they listed them with /mentions
+    pub fn clear_unread_mentions(&mut self, user: UserId) {
+        self.unread_mentions.remove(&user);
+    }
+
+
+    /// The first message of the thread a reply to the message with the id `parent` belongs to
+    pub fn thread_root(&self, room: RoomId, parent: MessageId) -> Result<MessageId, MessageError> {
+        let parent_message = self.get_chat(room)
+            .and_then(|chat| self.history.get(&chat.name, parent))
+            .filter(|message| message.msg_type == MessageType::User)
+            .ok_or(MessageError::NotFound(parent))?;
+        Ok(parent_message.thread.unwrap_or(parent))
+    }
+
+
+    /// Counts a new reply in the thread started by the message with the id `root`.
+    /// Returns the new reply count, or None if the first message isn't stored anymore
+    pub fn add_reply(&mut self, room: RoomId, root: MessageId) -> Option<usize> {
+        let chat = self.get_chat(room)?;
+        let mut message = self.history.get(&chat.name, root)?.clone();
+        message.reply_count += 1;
+        self.record_change(room, &message);
+        Some(message.reply_count)
+    }
+
+
+    /// Finds which of the rooms the user is in has the message with the id
+    pub fn find_message_room(&self, user: UserId, id: MessageId) -> Option<RoomId> {
+        self.get_chats_from_user(user).into_iter()
+            .find(|chat| self.history.get(&chat.name, id).is_some())
+            .map(|chat| chat.id)
+    }
+
+
+    /// Finds the room a message of the user should go to.
+    /// That is the requested room (if the user is in it) or otherwise the users active room
+    pub fn get_target_room(&self, user: UserId, requested: Option<&str>) -> Result<RoomId, RoomError> {
+        match requested {
+            Some(name) => {
+                let chat = self.get_chat_from_name(name)
+                    .ok_or_else(|| RoomError::NotFound(name.to_string()))?;
+                if !chat.members.contains(&user) {
+                    return Err(RoomError::NotMember(chat.name.clone()));
                 };
-                if !other_user_in_chat {
-                    let new_chat = Chat {
-                        members: vec![joining_user.addr, other_user_addr],
+                Ok(chat.id)
+            },
+            None => self.get_active_room(user).ok_or(RoomError::NoActiveRoom),
+        }
+    }
+
+
+    pub fn get_active_room(&self, user: UserId) -> Option<RoomId> {
+        self.active_rooms.get(&user).copied()
+    }
+
+
+    /// Makes the room the one the user is talking in. The user has to be a member of it
+    pub fn switch_chat(&mut self, user: &User, name: &str) -> Result<RoomId, RoomError> {
+        let room = self.get_target_room(user.id, Some(name))?;
+        self.active_rooms.insert(user.id, room);
+        Ok(room)
+    }
+
+
+    /// Sends the message to every member of a single room, optionally skipping one user.
+    /// Unlike `broadcast`, the message isn't recorded and doesn't get a sequence number
+    pub fn send_to_chat(&mut self, room: RoomId, message: &Message, skip: Option<UserId>) {
+        let mut message = message.clone();
+        self.stamp(&mut message);
+        let chat = match self.get_chat(room) {
+            Some(chat) => chat,
+            None => return,
+        };
+        message.room = Some(chat.name.clone());
+        let send : String = message.into();
+        for member in chat.members.iter() {
+            if Some(*member) == skip {
+                continue;
+            };
+            if let Some(peer) = self.peers.get(member) {
+                let _ = peer.transmitter.send(send.clone());
+            };
+        }
+    }
+
+
+    /// Tells the other members of the room that the user is typing, if their client can show it.
+    /// Unlike other messages the notice is neither stored nor gets an id
+    pub fn send_typing(&self, typist: &User, room: RoomId) {
+        let message = Message {
+            msg_type: MessageType::Typing,
+            author: typist.usrname.clone(),
+            ..Message::new()
+        };
+        self.send_to_supporting(room, message, Capability::Typing, Some(typist.id));
+    }
+
+
+    /// Records how far the user got in the room. Receipts only ever move forward,
+    /// so a late or repeated one doesn't undo anything. Returns whether anything changed
+    pub fn update_receipt(&mut self, user: UserId, room: RoomId, receipt: Receipt) -> bool {
+        let name = match self.get_chat(room) {
+            Some(chat) => chat.name.clone(),
+            None => return false,
+        };
+        let stored = self.receipts.entry(user).or_default().entry(name).or_default();
+        let updated = stored.furthest(receipt);
+        let changed = updated != *stored;
+        *stored = updated;
+        changed
+    }
+
+
+    /// How far every member of the room got, keyed by username
+    pub fn room_receipts(&self, room: RoomId) -> BTreeMap<String, Receipt> {
+        let chat = match self.get_chat(room) {
+            Some(chat) => chat,
+            None => return BTreeMap::new(),
+        };
+        chat.members.iter()
+            .filter_map(|member| {
+                let receipt = self.receipts.get(member)?.get(&chat.name)?;
+                Some((self.get_usr(*member)?.usrname.clone(), *receipt))
+            })
+            .collect()
+    }
+
+
+    /// Tells the members of the room that support it how far everyone got.
+    /// Big rooms don't get receipts, as every message would be followed by one from each member
+    pub fn send_receipts(&self, room: RoomId) {
+        let too_big = self.get_chat(room).is_none_or(|chat| chat.members.len() > self.config.receipts_room_limit);
+        if too_big {
+            return;
+        };
+        let message = Message {
+            msg_type: MessageType::Receipts { receipts: self.room_receipts(room) },
+            ..Message::new()
+        };
+        self.send_to_supporting(room, message, Capability::Receipts, None);
+    }
+
+
+    /// Changes the presence and/or status text of a connected user and tells everyone sharing a room with them.
+    /// Returns the new status
+    pub fn set_status(&mut self, user: UserId, presence: Option<Presence>, text: Option<String>) -> Option<Status> {
+        let peer = self.peers.get_mut(&user)?;
+        if let Some(presence) = presence {
+            peer.status.presence = presence;
+        };
+        if let Some(text) = text {
+            peer.status.text = if text.is_empty() { None } else { Some(text) };
+        };
+        let status = peer.status.clone();
+        self.send_presence(user, &status);
+        Some(status)
+    }
+
+
+    /// Tells the user and everyone sharing a room with them about the status of the user.
+    /// Also used to tell everyone that a user who is about to disconnect is offline
+    pub fn send_presence(&self, user: UserId, status: &Status) {
+        let username = match self.get_usr(user) {
+            Some(user) => user.usrname.clone(),
+            None => return,
+        };
+        let mut recipients : BTreeSet<UserId> = self.get_chats_from_user(user).into_iter()
+            .flat_map(|chat| chat.members.iter().copied())
+            .collect();
+        recipients.insert(user);
+        let send : String = Message {
+            msg_type: MessageType::PresenceUpdate(status.clone()),
+            author: username,
+            author_id: Some(user),
+            ..Message::new()
+        }.into();
+        for recipient in recipients {
+            match self.peers.get(&recipient) {
+                Some(peer) if peer.capabilities.contains(&Capability::Presence) => {
+                    let _ = peer.transmitter.send(send.clone());
+                },
+                _ => (),
+            };
+        }
+    }
+
+
+    /// Tells the members of the room about the status of a user who just joined it,
+    /// and the newcomer about the status of everyone else
+    pub fn send_room_presence(&self, room: RoomId, newcomer: UserId) {
+        let chat = match self.get_chat(room) {
+            Some(chat) => chat,
+            None => return,
+        };
+        let newcomer_peer = match self.peers.get(&newcomer) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let newcomer_status = Message {
+            msg_type: MessageType::PresenceUpdate(newcomer_peer.status.clone()),
+            author: newcomer_peer.user.usrname.clone(),
+            author_id: Some(newcomer),
+            ..Message::new()
+        };
+        self.send_to_supporting(room, newcomer_status, Capability::Presence, None);
+        if !newcomer_peer.capabilities.contains(&Capability::Presence) {
+            return;
+        };
+        for member in chat.members.iter().filter(|member| **member != newcomer) {
+            if let Some(peer) = self.peers.get(member) {
+                let send : String = Message {
+                    msg_type: MessageType::PresenceUpdate(peer.status.clone()),
+                    author: peer.user.usrname.clone(),
+                    author_id: Some(*member),
+                    ..Message::new()
+                }.into();
+                let _ = newcomer_peer.transmitter.send(send);
+            };
+        }
+    }
+
+
+    /// Gets ready to receive a file the user wants to send, if it fits in the limits.
+    /// Returns the id of the transfer the chunks have to carry
+    pub fn offer_file(&mut self, sender: UserId, target: TransferTarget, file: FileInfo) -> Result<TransferId, TransferError> {
+        // The name ends up as a path on the receiving side, so it mustn't lead anywhere else
+        if transfer::file_name(Path::new(&file.name)).as_ref() != Some(&file.name) {
+            return Err(TransferError::InvalidName(file.name));
+        };
+        if file.size == 0 {
+            return Err(TransferError::Empty);
+        };
+        if file.size > self.config.max_file_size {
+            return Err(TransferError::TooBig { size: file.size, limit: self.config.max_file_size });
+        };
+        let used : u64 = self.transfers.values()
+            .filter(|transfer| transfer.sender == sender)
+            .map(|transfer| transfer.file.size)
+            .sum();
+        if used + file.size > self.config.file_quota {
+            return Err(TransferError::QuotaExceeded { quota: self.config.file_quota });
+        };
+        if let TransferTarget::User(recipient) = target {
+            match self.peers.get(&recipient) {
+                Some(peer) if peer.capabilities.contains(&Capability::FileTransfer) => (),
+                Some(peer) => return Err(TransferError::CantReceive(peer.user.usrname.clone())),
+                None => return Err(TransferError::CantReceive(String::from("The user"))),
+            };
+        };
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        self.transfers.insert(id, Transfer {
+            id,
+            sender,
+            target,
+            file: file.clone(),
+            upload: Some(Assembly::new(file)),
+            data: Arc::new(Vec::new()),
+        });
+        Ok(id)
+    }
+
+
+    /// Adds the next chunk to a file the user is uploading. Returns the transfer once the file is complete.
+    /// Anything wrong with the chunk ends the transfer, the sender has to start over
+    pub fn add_file_chunk(&mut self, sender: UserId, id: TransferId, seq: u64, data: &str, checksum: &str) -> Result<Option<Transfer>, TransferError> {
+        let upload = match self.transfers.get_mut(&id) {
+            Some(Transfer { sender: owner, upload: Some(upload), .. }) if *owner == sender => upload,
+            _ => return Err(TransferError::Unknown(id)),
+        };
+        match upload.add_chunk(seq, data, checksum) {
+            Ok(None) => Ok(None),
+            Ok(Some(data)) => {
+                let transfer = self.transfers.get_mut(&id).ok_or(TransferError::Unknown(id))?;
+                transfer.upload = None;
+                transfer.data = Arc::new(data);
+                Ok(Some(transfer.clone()))
+            },
+            Err(err) => {
+                self.transfers.remove(&id);
+                Err(err)
+            },
+        }
+    }
+
+
+    /// The uploaded file, if the user may download it: because it was sent to them,
+    /// to a room they are in or by them
+    pub fn downloadable_file(&self, user: UserId, id: TransferId) -> Result<(FileInfo, Arc<Vec<u8>>), TransferError> {
+        let transfer = self.transfers.get(&id).ok_or(TransferError::Unknown(id))?;
+        let allowed = transfer.sender == user || match &transfer.target {
+            TransferTarget::User(recipient) => *recipient == user,
+            TransferTarget::Room(name) => self.get_chat_from_name(name).is_some_and(|chat| chat.members.contains(&user)),
+        };
+        if !allowed {
+            return Err(TransferError::Unknown(id));
+        };
+        if !transfer.is_complete() {
+            return Err(TransferError::NotReady(id));
+        };
+        Ok((transfer.file.clone(), Arc::clone(&transfer.data)))
+    }
+
+
+    /// Tells everyone the file was sent to that they can download it now, if their client can
+    pub fn send_file_offer(&self, transfer: &Transfer) {
+        let sender = match self.get_usr(transfer.sender) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let message = Message {
+            text: format!(
+                "offers {} ({}), type /accept {} to download it",
+                transfer.file.name, transfer::format_size(transfer.file.size), transfer.id,
+            ),
+            msg_type: MessageType::FileOffer { id: Some(transfer.id), file: transfer.file.clone() },
+            author: sender.usrname.clone(),
+            author_id: Some(sender.id),
+            timestamp: Some(Utc::now()),
+            ..Message::new()
+        };
+        match &transfer.target {
+            TransferTarget::Room(name) => {
+                if let Some(chat) = self.get_chat_from_name(name) {
+                    self.send_to_supporting(chat.id, message, Capability::FileTransfer, Some(sender.id));
+                };
+            },
+            TransferTarget::User(recipient) => match self.peers.get(recipient) {
+                Some(peer) if peer.capabilities.contains(&Capability::FileTransfer) => {
+                    let send : String = Message {
+                        recipient: Some(peer.user.usrname.clone()),
+                        ..message
+                    }.into();
+                    let _ = peer.transmitter.send(send);
+                },
+                _ => (),
+            },
+        };
+    }
+
+
+    /// Drops the files the user sent, as nobody can get them from the server once they are gone.
+    /// Downloads that already started still finish
+    pub fn remove_transfers(&mut self, sender: UserId) {
+        self.transfers.retain(|_, transfer| transfer.sender != sender);
+    }
+
+
+    /// Sends the message to the members of the room whose client supports the capability.
+    /// Messages sent this way are neither stored nor get an id
+    fn send_to_supporting(&self, room: RoomId, message: Message, capability: Capability, skip: Option<UserId>) {
+        let chat = match self.get_chat(room) {
+            Some(chat) => chat,
+            None => return,
+        };
+        let send : String = Message {
+            room: Some(chat.name.clone()),
+            ..message
+        }.into();
+        for member in chat.members.iter().filter(|member| Some(**member) != skip) {
+            match self.peers.get(member) {
+                Some(peer) if peer.capabilities.contains(&capability) => {
+                    let _ = peer.transmitter.send(send.clone());
+                },
+                _ => (),
+            };
+        }
+    }
+
+
+    /// Sends the message straight to a single user, bypassing any room
+    /// Returns false if the user isn't connected
+    pub fn send_to_user(&mut self, user: UserId, message: &Message) -> bool {
+        let mut message = message.clone();
+        self.stamp(&mut message);
+        match self.peers.get(&user) {
+            Some(peer) => {
+                let send : String = message.into();
+                peer.transmitter.send(send).is_ok()
+            },
+            None => false,
+        }
+    }
+
+
+    /// Joins the chat based on another users username
+    /// Returns the id of the chat that was joined
+    pub fn join_chat(&mut self, joining_user: &User, joined_user: &str) -> Result<RoomId, RoomError> {
+        let other_user_id = self.get_usr_from_name(joined_user.to_string()).map(|user| user.id);
+        match other_user_id {
+            Some(other_user_id) => {
+                // Other user is already part of a chat
+                if let Some(chat) = self.chats.iter_mut().find(|chat| chat.members.contains(&other_user_id)) {
+                    if chat.is_banned(joining_user) {
+                        return Err(RoomError::Banned(chat.name.clone()));
+                    };
+                    if !chat.members.contains(&joining_user.id) {
+                        chat.members.push(joining_user.id);
                     };
-                    self.chats.push(new_chat);
+                    let id = chat.id;
+                    self.active_rooms.insert(joining_user.id, id);
+                    return Ok(id);
                 };
+                let id = self.push_unnamed_chat();
+                let chat = self.get_mut_chat(id).unwrap();
+                chat.members = vec![joining_user.id, other_user_id];
+                // The friend keeps talking in whatever room they were in before
+                self.active_rooms.entry(other_user_id).or_insert(id);
+                self.active_rooms.insert(joining_user.id, id);
+                Ok(id)
             },
             None => {
                 eprintln!("Specified friend user doesn't exist! Creating new chat");
-                let new_chat = Chat {
-                    members: vec![joining_user.addr],
-                };
-                self.chats.push(new_chat);
+                let id = self.push_unnamed_chat();
+                self.get_mut_chat(id).unwrap().members.push(joining_user.id);
+                self.active_rooms.insert(joining_user.id, id);
+                Ok(id)
+            },
+        }
+    }
+
+
+    /// Creates a new named room and makes the creator its first member
+    pub fn create_chat(&mut self, creator: &User, name: &str, topic: String) -> Result<RoomId, RoomError> {
+        let name = normalize_room_name(name)?;
+        if self.get_chat_from_name(&name).is_some() {
+            return Err(RoomError::AlreadyExists(name));
+        };
+        let id = self.next_room_id;
+        self.next_room_id += 1;
+        let mut chat = Chat::new(id, name, topic);
+        // The room might have existed with the same name before
+        chat.last_seq = self.history.last_seq(&chat.name).unwrap_or(0);
+        chat.members.push(creator.id);
+        chat.owner = Some(creator.id);
+        self.chats.push(chat);
+        self.active_rooms.insert(creator.id, id);
+        Ok(id)
+    }
+
+
+    /// Joins an existing room by its name
+    pub fn join_chat_by_name(&mut self, joining_user: &User, name: &str) -> Result<RoomId, RoomError> {
+        let name = normalize_room_name(name)?;
+        let chat = self.chats.iter_mut().find(|chat| chat.name == name)
+            .ok_or_else(|| RoomError::NotFound(name.clone()))?;
+        if chat.members.contains(&joining_user.id) {
+            return Err(RoomError::AlreadyMember(name));
+        };
+        if chat.is_banned(joining_user) {
+            return Err(RoomError::Banned(name));
+        };
+        chat.members.push(joining_user.id);
+        let id = chat.id;
+        self.active_rooms.insert(joining_user.id, id);
+        Ok(id)
+    }
+
+
+    /// Leaves a room by its name. The room gets removed once it is empty, unless it is moderated (see `Chat::is_moderated`)
+    /// Returns the room as it was right after the user left
+    pub fn leave_chat(&mut self, leaving_user: &User, name: &str) -> Result<Chat, RoomError> {
+        let name = normalize_room_name(name)?;
+        let index = self.chats.iter().position(|chat| chat.name == name)
+            .ok_or_else(|| RoomError::NotFound(name.clone()))?;
+        let chat = &mut self.chats[index];
+        let userindex = chat.members.iter().position(|x| x == &leaving_user.id)
+            .ok_or(RoomError::NotMember(name))?;
+        chat.members.remove(userindex);
+        let chat = chat.clone();
+        if chat.is_abandoned(Utc::now()) {
+            self.chats.remove(index);
+        };
+        // Fall back to any other room the user is still in
+        if self.get_active_room(leaving_user.id) == Some(chat.id) {
+            match self.get_chats_from_user(leaving_user.id).first().map(|chat| chat.id) {
+                Some(other) => self.active_rooms.insert(leaving_user.id, other),
+                None => self.active_rooms.remove(&leaving_user.id),
+            };
+        };
+        Ok(chat)
+    }
+
+
+    /// The role of the user on the whole server. Guests are always guests,
+    /// the admins named in the config always admins
+    pub fn server_role(&self, user: UserId) -> Role {
+        match self.accounts.get_by_id(user) {
+            Some(account) if self.is_admin_name(&account.username) => Role::Admin,
+            Some(account) => account.role,
+            None => Role::Guest,
+        }
+    }
+
+
+    /// Whether the server config makes the account with the name an admin
+    fn is_admin_name(&self, name: &str) -> bool {
+        self.config.admins.iter().any(|admin| admin.eq_ignore_ascii_case(name))
+    }
+
+
+    /// The role of the user in the room: the one the room gives them if there is one, otherwise
+    /// their server wide role. The owner and operators of a room are at least moderators in it
+    pub fn role_in(&self, user: UserId, room: Option<RoomId>) -> Role {
+        let role = self.server_role(user);
+        match room.and_then(|room| self.get_chat(room)) {
+            Some(chat) => match chat.roles.get(&user) {
+                Some(role) => *role,
+                None if chat.is_moderator(user) => role.max(Role::Moderator),
+                None => role,
+            },
+            None => role,
+        }
+    }
+
+
+    /// Whether the user has the permission, in the room if there is one and the permission is about rooms
+    pub fn check_permission(&self, user: UserId, room: Option<RoomId>, permission: Permission) -> Result<(), PermissionError> {
+        let required = self.config.required_role(permission);
+        let room = room.filter(|_| permission.in_rooms());
+        if self.role_in(user, room) < required {
+            return Err(PermissionError::Denied { permission, required });
+        };
+        Ok(())
+    }
+
+
+    /// Whether the user may send messages to the room. Read-only rooms need more than that
+    pub fn check_may_post(&self, user: UserId, room: RoomId) -> Result<(), PermissionError> {
+        self.check_permission(user, Some(room), Permission::SendMessages)?;
+        if self.get_chat(room).is_some_and(|chat| chat.read_only) {
+            self.check_permission(user, Some(room), Permission::PostAnnouncements)?;
+        };
+        Ok(())
+    }
+
+
+    /// Fails if the user is muted in the room. New messages are checked by `broadcast`,
+    /// anything else that reaches the whole room (edits, files) has to check it itself
+    pub fn check_not_muted(&self, user: UserId, room: RoomId) -> Result<(), RoomError> {
+        match self.get_chat(room) {
+            Some(chat) if chat.is_muted(user, Utc::now()) => Err(RoomError::Muted(chat.name.clone())),
+            _ => Ok(()),
+        }
+    }
+
+
+    /// Gives a registered user another role on the whole server, or back the default one.
+    /// Needs `Permission::ManageRoles`, and nobody can give out a role above their own
+    pub fn set_server_role(&mut self, setter: UserId, username: &str, role: Option<Role>) -> Result<Role, PermissionError> {
+        self.check_permission(setter, None, Permission::ManageRoles)?;
+        let role = role.unwrap_or_default();
+        let own = self.server_role(setter);
+        if role > own {
+            return Err(PermissionError::AboveOwnRole(role));
+        };
+        let user = self.accounts.get(username).map(|account| account.id)
+            .ok_or_else(|| PermissionError::NoAccount(username.to_string()))?;
+        if user == setter || self.server_role(user) > own {
+            return Err(PermissionError::Protected(username.to_string()));
+        };
+        if let Err(err) = self.accounts.set_role(user, role) {
+            eprintln!("Could not change the role of {}: {}", username, err);
+        };
+        Ok(self.server_role(user))
+    }
+
+
+    /// Gives a connected user another role in the room, or takes the room's role away with None.
+    /// The owner of the room may do that, and whoever may give out roles on the server
+    pub fn set_room_role(&mut self, setter: UserId, room: RoomId, username: &str, role: Option<Role>) -> Result<Role, PermissionError> {
+        let owner = self.get_chat(room).is_some_and(|chat| chat.owner == Some(setter));
+        if !owner {
+            self.check_permission(setter, None, Permission::ManageRoles)?;
+        };
+        if role.is_some_and(|role| role > self.role_in(setter, Some(room))) {
+            return Err(PermissionError::AboveOwnRole(role.unwrap_or_default()));
+        };
+        let user = self.get_usr_from_name(username.to_string()).map(|user| user.id)
+            .filter(|user| self.outranks(setter, *user, room))
+            .ok_or_else(|| PermissionError::Protected(username.to_string()))?;
+        if let Some(chat) = self.get_mut_chat(room) {
+            match role {
+                Some(role) => chat.roles.insert(user, role),
+                None => chat.roles.remove(&user),
+            };
+        };
+        Ok(self.role_in(user, Some(room)))
+    }
+
+
+    /// Turns the room into a read-only announcement room, or back into a normal one.
+    /// Returns whether it is read-only now
+    pub fn toggle_read_only(&mut self, user: UserId, room: RoomId) -> Result<bool, RoomError> {
+        let name = self.get_chat(room).map(|chat| chat.name.clone()).ok_or(RoomError::NoActiveRoom)?;
+        if self.check_permission(user, Some(room), Permission::ManageRooms).is_err() {
+            return Err(RoomError::NotModerator(name));
+        };
+        let chat = self.get_mut_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        chat.read_only = !chat.read_only;
+        Ok(chat.read_only)
+    }
+
+
+    /// Removes a member from the room. Returns the user that was kicked
+    pub fn kick(&mut self, moderator: UserId, room: RoomId, username: &str) -> Result<User, RoomError> {
+        let (name, user) = self.moderated_member(moderator, room, username)?;
+        self.leave_chat(&user, &name)?;
+        Ok(user)
+    }
+
+
+    /// Keeps the user out of the room, and everyone connecting from their address if `by_address` is set.
+    /// Users that aren't connected can only be banned if they have an account.
+    /// Returns the user if they were in the room and got removed from it
+    pub fn ban(&mut self, moderator: UserId, room: RoomId, username: &str, by_address: bool) -> Result<Option<User>, RoomError> {
+        let chat = self.get_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        if self.check_permission(moderator, Some(room), Permission::ModerateRooms).is_err() {
+            return Err(RoomError::NotModerator(chat.name.clone()));
+        };
+        let connected = self.get_usr_from_name(username.to_string()).cloned();
+        let user = match (&connected, self.accounts.get(username)) {
+            (Some(user), _) => user.id,
+            (None, Some(account)) if !by_address => account.id,
+            _ => return Err(RoomError::UnknownMember(username.to_string())),
+        };
+        if !self.outranks(moderator, user, room) {
+            return Err(RoomError::Protected(username.to_string()));
+        };
+        let name = chat.name.clone();
+        let ban = Ban {
+            user,
+            username: username.to_string(),
+            addr: connected.as_ref().filter(|_| by_address).map(|user| user.addr.ip()),
+        };
+        if let Some(chat) = self.get_mut_chat(room) {
+            chat.operators.remove(&user);
+            chat.bans.insert(user, ban);
+        };
+        match connected {
+            Some(user) if self.get_chat(room).is_some_and(|chat| chat.members.contains(&user.id)) => {
+                self.leave_chat(&user, &name)?;
+                Ok(Some(user))
             },
+            _ => Ok(None),
         }
     }
 
 
-    pub fn get_chats_from_user_addr(&self, addr: SocketAddr) -> Vec<&Chat> {
+    /// Lets a banned user into the room again
+    pub fn unban(&mut self, moderator: UserId, room: RoomId, username: &str) -> Result<(), RoomError> {
+        let name = self.get_chat(room).map(|chat| chat.name.clone()).ok_or(RoomError::NoActiveRoom)?;
+        if self.check_permission(moderator, Some(room), Permission::ModerateRooms).is_err() {
+            return Err(RoomError::NotModerator(name));
+        };
+        let user = self.get_usr_from_name(username.to_string()).map(|user| user.id)
+            .or_else(|| self.accounts.get(username).map(|account| account.id));
+        let chat = self.get_mut_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        // Guests that left are only known by the name they were banned with
+        let banned = user.filter(|user| chat.bans.contains_key(user))
+            .or_else(|| chat.bans.values().find(|ban| ban.username.eq_ignore_ascii_case(username)).map(|ban| ban.user));
+        banned.and_then(|user| chat.bans.remove(&user))
+            .map(|_| ())
+            .ok_or_else(|| RoomError::NotBanned(username.to_string()))
+    }
+
+
+    /// Stops a member from sending messages to the room, for the duration or until they are unmuted.
+    /// Returns the muted user
+    pub fn mute(&mut self, moderator: UserId, room: RoomId, username: &str, duration: Option<std::time::Duration>) -> Result<User, RoomError> {
+        let (_, user) = self.moderated_member(moderator, room, username)?;
+        // Durations too long to add up to a date mute for good
+        let until = duration
+            .and_then(|duration| TimeDelta::from_std(duration).ok())
+            .and_then(|duration| Utc::now().checked_add_signed(duration));
+        if let Some(chat) = self.get_mut_chat(room) {
+            chat.muted.insert(user.id, until);
+        };
+        Ok(user)
+    }
+
+
+    /// Lets a muted member send messages again. Returns the user
+    pub fn unmute(&mut self, moderator: UserId, room: RoomId, username: &str) -> Result<User, RoomError> {
+        let (_, user) = self.moderated_member(moderator, room, username)?;
+        if let Some(chat) = self.get_mut_chat(room) {
+            chat.muted.remove(&user.id);
+        };
+        Ok(user)
+    }
+
+
+    /// Makes a member an operator of the room, or takes it back if they are one already.
+    /// Only the owner (and whoever may give out roles) may do that. Returns the user and whether they are an operator now
+    pub fn toggle_operator(&mut self, owner: UserId, room: RoomId, username: &str) -> Result<(User, bool), RoomError> {
+        let chat = self.get_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        if chat.owner != Some(owner) && self.check_permission(owner, None, Permission::ManageRoles).is_err() {
+            return Err(RoomError::NotOwner(chat.name.clone()));
+        };
+        let (_, user) = self.moderated_member(owner, room, username)?;
+        let chat = self.get_mut_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        let operator = !chat.operators.remove(&user.id);
+        if operator {
+            chat.operators.insert(user.id);
+        };
+        Ok((user, operator))
+    }
+
+
+    /// Removes the user from every room it is in (and every room that is abandoned now, see `Chat::is_abandoned`)
+    /// Returns the ids of the rooms that still have members left
+    pub fn leave_all_chats(&mut self, user: UserId) -> Vec<RoomId> {
+        let mut remaining = Vec::new();
+        for chat in self.get_mut_chats_from_user(user) {
+            chat.members.retain(|member| member != &user);
+            if !chat.members.is_empty() {
+                remaining.push(chat.id);
+            };
+        };
+        let now = Utc::now();
+        self.chats.retain(|chat| !chat.is_abandoned(now));
+        self.active_rooms.remove(&user);
+        remaining
+    }
+
+
+    pub fn get_chat(&self, id: RoomId) -> Option<&Chat> {
+        self.chats.iter().find(|chat| chat.id == id)
+    }
+
+
+    pub fn get_mut_chat(&mut self, id: RoomId) -> Option<&mut Chat> {
+        self.chats.iter_mut().find(|chat| chat.id == id)
+    }
+
+
+    pub fn get_chat_from_name(&self, name: &str) -> Option<&Chat> {
+        let name = normalize_room_name(name).ok()?;
+        self.chats.iter().find(|chat| chat.name == name)
+    }
+
+
+    pub fn get_chats_from_user(&self, user: UserId) -> Vec<&Chat> {
         let mut chats = vec![];
         for chat in self.chats.iter() {
-            if chat.members.contains(&addr) {
+            if chat.members.contains(&user) {
                 chats.push(chat);
             }
         }
@@ -128,13 +1246,184 @@ impl Shared {
     }
 
 
-    pub fn get_mut_chats_from_user_addr(&mut self, addr: SocketAddr) -> Vec<&mut Chat> {
+    pub fn get_mut_chats_from_user(&mut self, user: UserId) -> Vec<&mut Chat> {
         let mut chats = vec![];
         for chat in self.chats.iter_mut() {
-            if chat.members.contains(&addr) {
+            if chat.members.contains(&user) {
                 chats.push(chat);
             }
         }
         chats
     }
+
+
+    /// Creates a chat for the join-by-username shortcut, which gets a generated name
+    fn push_unnamed_chat(&mut self) -> RoomId {
+        let mut id = self.next_room_id;
+        // Someone might have already created a room with the generated name, or a chat before
+        // a restart might have had it. Its history is none of the new members' business
+        let is_taken = |name: &str| self.get_chat_from_name(name).is_some() || self.history.messages(name).next().is_some();
+        while is_taken(&format!("chat-{}", id)) {
+            id += 1;
+        };
+        self.next_room_id = id + 1;
+        self.chats.push(Chat::new(id, format!("chat-{}", id), String::new()));
+        id
+    }
+
+
+    /// Whether the moderator may kick, ban or mute the user in the room, or change their role there:
+    /// only users with a lower role in it, and the owner also operators
+    fn outranks(&self, moderator: UserId, user: UserId, room: RoomId) -> bool {
+        let (ours, theirs) = (self.role_in(moderator, Some(room)), self.role_in(user, Some(room)));
+        let owner = self.get_chat(room).is_some_and(|chat| chat.owner == Some(moderator));
+        moderator != user && (ours > theirs || (ours == theirs && owner))
+    }
+
+
+    /// The name of the room and the member the moderator wants to act on,
+    /// if the moderator may do that to them
+    fn moderated_member(&self, moderator: UserId, room: RoomId, username: &str) -> Result<(String, User), RoomError> {
+        let chat = self.get_chat(room).ok_or(RoomError::NoActiveRoom)?;
+        if self.check_permission(moderator, Some(room), Permission::ModerateRooms).is_err() {
+            return Err(RoomError::NotModerator(chat.name.clone()));
+        };
+        let user = self.get_usr_from_name(username.to_string())
+            .filter(|user| chat.members.contains(&user.id))
+            .ok_or_else(|| RoomError::UnknownMember(username.to_string()))?;
+        if !self.outranks(moderator, user.id, room) {
+            return Err(RoomError::Protected(user.usrname.clone()));
+        };
+        Ok((chat.name.clone(), user.clone()))
+    }
+
+
+    /// A copy of a message users wrote in the room that wasn't deleted yet
+    fn get_changeable_message(&self, room: RoomId, id: MessageId) -> Result<Message, MessageError> {
+        let chat = self.get_chat(room).ok_or(MessageError::NotFound(id))?;
+        let message = self.history.get(&chat.name, id)
+            .filter(|message| message.msg_type == MessageType::User)
+            .ok_or(MessageError::NotFound(id))?;
+        if message.deleted {
+            return Err(MessageError::AlreadyDeleted);
+        };
+        Ok(message.clone())
+    }
+
+
+    /// Puts the changed message into the history in place of the old one
+    fn record_change(&mut self, room: RoomId, message: &Message) {
+        let name = match self.get_chat(room) {
+            Some(chat) => chat.name.clone(),
+            None => return,
+        };
+        if let Err(err) = self.history.replace(&name, message) {
+            eprintln!("Could not record the change of message {:?} in #{}: {}", message.id, name, err);
+        };
+    }
+
+
+    /// Gives a message that doesn't go through `broadcast` an id and the current time
+    fn stamp(&mut self, message: &mut Message) {
+        message.id = Some(self.next_message_id);
+        message.timestamp = Some(Utc::now());
+        self.next_message_id += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server where the account called root is an admin
+    fn state() -> Shared {
+        let config = ServerConfig { admins: vec![String::from("root")], ..ServerConfig::default() };
+        let history = Box::new(MemoryHistory::new(config.history_capacity));
+        Shared::with_config(config, AccountStore::in_memory(), history)
+    }
+
+    /// Connects a user, with an account if `registered` is set
+    fn connect(state: &mut Shared, name: &str, registered: bool) -> User {
+        let user = User {
+            id: state.next_user_id(),
+            conn: state.next_connection_id(),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            usrname: name.to_string(),
+        };
+        if registered {
+            state.accounts.register(user.id, name, String::new()).unwrap();
+        };
+        state.add_peer(user.clone(), BTreeSet::new()).unwrap();
+        user
+    }
+
+    #[test]
+    fn room_roles_only_count_for_room_permissions() {
+        let mut state = state();
+        let owner = connect(&mut state, "ann", false);
+        let room = state.create_chat(&owner, "#dev", String::new()).unwrap();
+        assert!(state.check_permission(owner.id, Some(room), Permission::ModerateRooms).is_ok());
+        assert!(state.check_permission(owner.id, None, Permission::ModerateRooms).is_err());
+        assert!(state.check_permission(owner.id, Some(room), Permission::ManageRoles).is_err());
+    }
+
+    #[test]
+    fn read_only_rooms_need_moderators_to_post() {
+        let mut state = state();
+        let owner = connect(&mut state, "ann", false);
+        let member = connect(&mut state, "bob", false);
+        let room = state.create_chat(&owner, "#news", String::new()).unwrap();
+        state.join_chat_by_name(&member, "#news").unwrap();
+        assert!(state.check_may_post(member.id, room).is_ok());
+        assert!(state.toggle_read_only(member.id, room).is_err());
+        assert!(state.toggle_read_only(owner.id, room).unwrap());
+        assert!(state.check_may_post(member.id, room).is_err());
+        assert!(state.check_may_post(owner.id, room).is_ok());
+    }
+
+    #[test]
+    fn muted_members_until_unmuted() {
+        let mut state = state();
+        let owner = connect(&mut state, "ann", false);
+        let member = connect(&mut state, "bob", false);
+        let room = state.create_chat(&owner, "#dev", String::new()).unwrap();
+        state.join_chat_by_name(&member, "#dev").unwrap();
+        assert!(state.mute(member.id, room, "ann", None).is_err());
+        state.mute(owner.id, room, "bob", None).unwrap();
+        assert!(matches!(state.check_not_muted(member.id, room), Err(RoomError::Muted(_))));
+        assert!(state.check_not_muted(owner.id, room).is_ok());
+        state.unmute(owner.id, room, "bob").unwrap();
+        assert!(state.check_not_muted(member.id, room).is_ok());
+    }
+
+    #[test]
+    fn nobody_gives_out_roles_above_their_own() {
+        let mut state = state();
+        let admin = connect(&mut state, "root", true);
+        let member = connect(&mut state, "bob", true);
+        connect(&mut state, "carol", true);
+        assert!(matches!(state.set_server_role(member.id, "carol", Some(Role::Member)), Err(PermissionError::Denied { .. })));
+        assert_eq!(state.set_server_role(admin.id, "bob", Some(Role::Moderator)), Ok(Role::Moderator));
+        assert_eq!(state.server_role(member.id), Role::Moderator);
+        assert!(matches!(state.set_server_role(admin.id, "root", None), Err(PermissionError::Protected(_))));
+        assert!(matches!(state.set_server_role(admin.id, "nobody", None), Err(PermissionError::NoAccount(_))));
+        assert_eq!(state.set_server_role(admin.id, "bob", None), Ok(Role::default()));
+    }
+
+    #[test]
+    fn bans_outlive_renames() {
+        let mut state = state();
+        let owner = connect(&mut state, "ann", false);
+        let member = connect(&mut state, "bob", false);
+        let room = state.create_chat(&owner, "#dev", String::new()).unwrap();
+        state.join_chat_by_name(&member, "#dev").unwrap();
+        assert!(state.ban(member.id, room, "ann", false).is_err());
+        assert_eq!(state.ban(owner.id, room, "bob", false).unwrap(), Some(member.clone()));
+        let member = state.rename_peer(&member, "rob").unwrap();
+        assert!(matches!(state.join_chat_by_name(&member, "#dev"), Err(RoomError::Banned(_))));
+        assert!(matches!(state.unban(owner.id, room, "carol"), Err(RoomError::NotBanned(_))));
+        state.unban(owner.id, room, "rob").unwrap();
+        assert!(state.join_chat_by_name(&member, "#dev").is_ok());
+    }
 }
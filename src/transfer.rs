@@ -0,0 +1,257 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use base64ct::{Base64, Encoding};
+use blake2::{Blake2s256, Digest};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::types::{MessageType, TransferId, UserId};
+
+
+/// Amount of bytes sent in a single chunk. Encoded as base64 a chunk stays well below `codec::MAX_LINE_LEN`
+pub const CHUNK_SIZE : usize = 16 * 1024;
+
+
+/// What the receiver of a file gets to know before downloading it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct FileInfo {
+    /// Name of the file without any directories
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Checksum of the whole file, see `checksum`
+    pub checksum: String,
+}
+
+impl FileInfo {
+    /// Describes the file with the data, only keeping the last part of the path as its name
+    pub fn new(path: &Path, data: &[u8]) -> FileInfo {
+        FileInfo {
+            name: file_name(path).unwrap_or_default(),
+            size: data.len() as u64,
+            checksum: checksum(data),
+        }
+    }
+
+
+    /// Amount of chunks the file is sent in
+    pub fn chunks(&self) -> u64 {
+        self.size.div_ceil(CHUNK_SIZE as u64)
+    }
+}
+
+
+/// Base64 encoded BLAKE2s hash of the data
+pub fn checksum(data: &[u8]) -> String {
+    Base64::encode_string(&Blake2s256::digest(data))
+}
+
+
+/// The last part of the path, which is all the receiver of a file gets to see of it.
+/// None if the path doesn't end in a name (e.g. `..`)
+pub fn file_name(path: &Path) -> Option<String> {
+    path.file_name().and_then(|name| name.to_str()).map(str::to_string)
+}
+
+
+/// The chunk with the number `seq` of the file, ready to send. None once all chunks are sent
+pub fn chunk(id: TransferId, data: &[u8], seq: u64) -> Option<MessageType> {
+    let start = usize::try_from(seq).ok()?.checked_mul(CHUNK_SIZE)?;
+    if start >= data.len() {
+        return None;
+    };
+    let chunk = &data[start..data.len().min(start + CHUNK_SIZE)];
+    Some(MessageType::FileChunk {
+        id,
+        seq,
+        data: Base64::encode_string(chunk),
+        checksum: checksum(chunk),
+    })
+}
+
+
+/// Sizes the way people read them, e.g. "1.5 MiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS : [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    };
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    };
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+
+/// Puts a file back together from its chunks, checking every chunk and the whole file at the end
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub file: FileInfo,
+    data: Vec<u8>,
+}
+
+impl Assembly {
+    pub fn new(file: FileInfo) -> Assembly {
+        Assembly {
+            file,
+            data: Vec::new(),
+        }
+    }
+
+
+    /// Adds the next chunk of the file. Chunks have to arrive in order.
+    /// Returns the whole file once its last chunk arrived
+    pub fn add_chunk(&mut self, seq: u64, data: &str, checksum: &str) -> Result<Option<Vec<u8>>, TransferError> {
+        let expected = (self.data.len() / CHUNK_SIZE) as u64;
+        if seq != expected {
+            return Err(TransferError::OutOfOrder { expected, got: seq });
+        };
+        let chunk = Base64::decode_vec(data).map_err(|_| TransferError::ChecksumMismatch(seq))?;
+        if self::checksum(&chunk) != checksum {
+            return Err(TransferError::ChecksumMismatch(seq));
+        };
+        self.data.extend_from_slice(&chunk);
+        if self.received() < self.file.size {
+            return Ok(None);
+        };
+        if self.received() > self.file.size || self::checksum(&self.data) != self.file.checksum {
+            return Err(TransferError::Corrupted);
+        };
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+
+
+    /// Amount of bytes that arrived so far
+    pub fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+
+/// Who may download a file
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TransferTarget {
+    /// Everyone in the room with this name
+    Room(String),
+    User(UserId),
+}
+
+
+/// A file the server keeps for others to download, while it is uploaded and after
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub id: TransferId,
+    pub sender: UserId,
+    pub target: TransferTarget,
+    pub file: FileInfo,
+    /// The chunks that arrived so far, until the upload is complete
+    pub upload: Option<Assembly>,
+    /// The whole file, once the upload is complete
+    pub data: Arc<Vec<u8>>,
+}
+
+impl Transfer {
+    pub fn is_complete(&self) -> bool {
+        self.upload.is_none()
+    }
+}
+
+
+/// Everything that can go wrong when sending a file
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TransferError {
+    Empty,
+    InvalidName(String),
+    TooBig { size: u64, limit: u64 },
+    /// All files the user sent together may not take more than the quota
+    QuotaExceeded { quota: u64 },
+    /// The receiver isn't connected or their client can't receive files
+    CantReceive(String),
+    /// The transfer doesn't exist (anymore), or isn't meant for the user
+    Unknown(TransferId),
+    /// The file is still being uploaded
+    NotReady(TransferId),
+    OutOfOrder { expected: u64, got: u64 },
+    /// A chunk got damaged on its way, holds its number
+    ChecksumMismatch(u64),
+    /// All chunks arrived, but together they aren't the file that was offered
+    Corrupted,
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::Empty => write!(f, "Empty files can't be sent"),
+            TransferError::InvalidName(name) => write!(f, "'{}' is not a valid file name", name),
+            TransferError::TooBig { size, limit } => write!(f, "The file has {}, but at most {} are allowed", format_size(*size), format_size(*limit)),
+            TransferError::QuotaExceeded { quota } => write!(f, "All your files together may not be bigger than {}", format_size(*quota)),
+            TransferError::CantReceive(name) => write!(f, "{} can't receive files right now", name),
+            TransferError::Unknown(id) => write!(f, "There is no file transfer {} for you", id),
+            TransferError::NotReady(id) => write!(f, "File transfer {} isn't uploaded completely yet", id),
+            TransferError::OutOfOrder { expected, got } => write!(f, "Expected chunk {} but got chunk {}", expected, got),
+            TransferError::ChecksumMismatch(seq) => write!(f, "Chunk {} got damaged on its way", seq),
+            TransferError::Corrupted => write!(f, "The file that arrived is not the one that was offered"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The chunk with the number `seq` of the data, as its data and checksum
+    fn chunk_of(data: &[u8], seq: u64) -> (String, String) {
+        match chunk(1, data, seq) {
+            Some(MessageType::FileChunk { data, checksum, .. }) => (data, checksum),
+            _ => panic!("the data has no chunk {}", seq),
+        }
+    }
+
+    #[test]
+    fn chunks_put_the_file_back_together() {
+        let data : Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let file = FileInfo::new(Path::new("dir/data.bin"), &data);
+        assert_eq!((file.name.as_str(), file.chunks()), ("data.bin", 3));
+        let mut assembly = Assembly::new(file);
+        for seq in 0..2 {
+            let (part, checksum) = chunk_of(&data, seq);
+            assert_eq!(assembly.add_chunk(seq, &part, &checksum), Ok(None));
+        };
+        let (part, checksum) = chunk_of(&data, 2);
+        assert_eq!(assembly.add_chunk(2, &part, &checksum), Ok(Some(data.clone())));
+        assert!(chunk(1, &data, 3).is_none());
+    }
+
+    #[test]
+    fn chunks_have_to_arrive_in_order() {
+        let data = vec![7; CHUNK_SIZE + 1];
+        let mut assembly = Assembly::new(FileInfo::new(Path::new("data.bin"), &data));
+        let (part, checksum) = chunk_of(&data, 1);
+        assert_eq!(assembly.add_chunk(1, &part, &checksum), Err(TransferError::OutOfOrder { expected: 0, got: 1 }));
+        assert_eq!(assembly.received(), 0);
+    }
+
+    #[test]
+    fn damaged_chunks_are_refused() {
+        let data = b"hello world".to_vec();
+        let mut assembly = Assembly::new(FileInfo::new(Path::new("hello.txt"), &data));
+        let (_, checksum) = chunk_of(&data, 0);
+        let damaged = Base64::encode_string(b"hello w0rld");
+        assert_eq!(assembly.add_chunk(0, &damaged, &checksum), Err(TransferError::ChecksumMismatch(0)));
+        assert_eq!(assembly.add_chunk(0, "not base64!", &checksum), Err(TransferError::ChecksumMismatch(0)));
+    }
+
+    #[test]
+    fn a_different_file_is_corrupted() {
+        let data = b"hello world".to_vec();
+        let mut assembly = Assembly::new(FileInfo::new(Path::new("hello.txt"), b"hello there"));
+        let (part, checksum) = chunk_of(&data, 0);
+        assert_eq!(assembly.add_chunk(0, &part, &checksum), Err(TransferError::Corrupted));
+    }
+}
@@ -1,15 +1,207 @@
 use serde_derive::{Serialize, Deserialize};
 
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::command::{Command, CommandOutcome};
+use crate::message::Message;
+use crate::protocol::Capability;
+use crate::transfer::FileInfo;
+
+
+/// Server side identifier of a chat room
+pub type RoomId = u64;
+
+/// Server assigned identifier of a message that was sent to a room
+pub type MessageId = u64;
+
+
+/// Who reacted to a message with which emoji, keyed by the emoji
+pub type Reactions = BTreeMap<String, BTreeSet<UserId>>;
+
+/// Client chosen identifier of a request, the server puts it on its answer (`MessageType::CommandResult`)
+pub type RequestId = u64;
+
+/// Server assigned identifier of a file transfer
+pub type TransferId = u64;
+
+
+/// How far a user got in a room
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Receipt {
+    /// Newest message of the room the client got
+    pub delivered: Option<MessageId>,
+    /// Newest message of the room the user has seen
+    pub read: Option<MessageId>,
+}
+
+impl Receipt {
+    /// The furthest of both receipts. A message that was read was delivered as well
+    pub fn furthest(self, other: Receipt) -> Receipt {
+        let read = self.read.max(other.read);
+        Receipt {
+            delivered: self.delivered.max(other.delivered).max(read),
+            read,
+        }
+    }
+}
+
+
+/// How available a user is
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub enum Presence {
+    #[default]
+    Online,
+    /// Set with /status or by the client after a while without any input
+    Away,
+    /// The user doesn't want to be disturbed
+    DoNotDisturb,
+    /// Not connected
+    Offline,
+}
+
+impl Presence {
+    /// Reads the presence as it is typed after /status. Offline can't be chosen, it only means not being connected
+    pub fn parse(input: &str) -> Option<Presence> {
+        match input.to_lowercase().as_str() {
+            "online" => Some(Presence::Online),
+            "away" => Some(Presence::Away),
+            "busy" | "dnd" => Some(Presence::DoNotDisturb),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away => write!(f, "away"),
+            Presence::DoNotDisturb => write!(f, "do not disturb"),
+            Presence::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+
+/// The presence of a user together with the status text they chose, e.g. "away: at lunch"
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Status {
+    pub presence: Presence,
+    pub text: Option<String>,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{}: {}", self.presence, text),
+            None => write!(f, "{}", self.presence),
+        }
+    }
+}
+
+
+/// Server assigned identity of a user. Unlike the address it doesn't depend on
+/// the connection the user happens to be using
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct UserId(pub u64);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "u{}", self.0)
+    }
+}
+
+
+/// Server assigned identifier of a single connection (TCP stream)
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct ConnectionId(pub u64);
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "c{}", self.0)
+    }
+}
+
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum MessageType {
+    /// First message of every connection, sent by the client
+    Hello { version: u32, client: String, capabilities: BTreeSet<Capability> },
+    /// Answer to `Hello` with the version and capabilities both sides agreed on.
+    /// If they can't agree, the server sends an `Error` instead and closes the connection
+    Welcome { version: u32, server: String, capabilities: BTreeSet<Capability> },
     SystemInfo,
     UserSetup(UserSetupType),
     User,
-    Command,
+    /// A command the user typed, the message text holds it as it was typed
+    Command(Command),
+    /// Answer to a `Command` that came with a `Message::request_id`, carrying the same id.
+    /// `command` is the name of the command, a failure also puts its reason in the message text
+    CommandResult { command: String, outcome: CommandOutcome },
+    /// Tells the client which room its messages currently go to (carried in `Message::room`)
+    ActiveRoom,
+    /// A direct message between two users that doesn't belong to any room
+    Private,
+    /// Changes the text of the message with the id to the text of this message.
+    /// Sent by the author, the server passes it on to everyone in the room
+    Edit { id: MessageId },
+    /// Removes the text of the message with the id. Sent by the author or a moderator of the room,
+    /// the server passes it on to everyone in the room
+    Delete { id: MessageId },
+    /// The reactions to the message with the id changed, these are all of them now
+    ReactionUpdate { id: MessageId, reactions: Reactions },
+    /// The thread started by the message with the id got a new reply
+    ThreadUpdate { id: MessageId, reply_count: usize },
+    /// Answer to /thread: the first message of the thread followed by all replies, oldest first
+    Thread { id: MessageId, messages: Vec<Message> },
+    /// The author is typing a message in the room. Sent by clients every few seconds while they type,
+    /// the server passes it on to the other members of the room that support `Capability::Typing` without storing it
+    Typing,
+    /// Sent by clients with `Capability::Receipts`: they got, and the user saw, the messages of the room up to these ids
+    Receipt(Receipt),
+    /// How far every member of the room got, keyed by username. Sent to the members of the room
+    /// that support `Capability::Receipts` whenever one of them moves on
+    Receipts { receipts: BTreeMap<String, Receipt> },
+    /// The status of the author changed (or they joined one of our rooms). Sent to everyone
+    /// sharing a room with them whose client supports `Capability::Presence`
+    PresenceUpdate(Status),
+    /// Offers a file to the room in `Message::room`, or to `Message::recipient`.
+    /// Clients send it without an id and a `Message::request_id`, the server answers with `UploadReady`
+    /// and then passes it on with the id to everyone allowed to download it who supports `Capability::FileTransfer`
+    FileOffer { id: Option<TransferId>, file: FileInfo },
+    /// The server is ready for the chunks of the offered file, carries the request id of the offer
+    UploadReady { id: TransferId },
+    /// Part of a file: base64 encoded data with the checksum of the decoded bytes.
+    /// Clients send them to upload a file, the server sends them after /accept
+    FileChunk { id: TransferId, seq: u64, data: String, checksum: String },
+    /// Answer to /accept, the chunks of the file follow
+    DownloadStart { id: TransferId, file: FileInfo },
+    /// The upload arrived completely and the file can be downloaded now
+    TransferDone { id: TransferId },
+    /// The transfer was given up, the reason is in the message text.
+    /// Without an id it answers the offer with the same request id
+    TransferFailed { id: Option<TransferId> },
+    /// Answer to /history: older messages of the room in `Message::room`, oldest first.
+    /// `has_more` tells whether there are even older ones left
+    HistoryBatch { messages: Vec<Message>, has_more: bool },
+    /// Answer to /search: the messages that matched the query, best match first
+    SearchResults { query: String, results: Vec<Message> },
+    /// Something the other side sent could not be understood, the reason is in the message text
+    Error,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum UserSetupType {
+    /// The user is logged in, the message text holds the final username
     UsernameConfirmed,
-}
\ No newline at end of file
+    /// Sent by the server right after connecting, the client has to answer with
+    /// `Login`, `Register` or (if allowed) `Guest`
+    LoginRequired { guests_allowed: bool },
+    Login { username: String, password: String },
+    Register { username: String, password: String },
+    Guest { username: String },
+    /// Logging in or registering didn't work, the reason is in the message text
+    LoginFailed,
+    /// A new account was created, the message text holds its username
+    Registered,
+}